@@ -1,265 +1,1326 @@
-use std::{net::{Ipv4Addr, SocketAddrV4, TcpStream}, path::{Path, PathBuf}, str::FromStr};
+mod profiles;
+mod queue;
 
-use iced::{application, widget::{button, column, container, row, text, text_input, vertical_space, Column}, Length, Task};
+use std::{collections::{BTreeSet, HashMap}, io::{Read, Write}, net::{SocketAddrV4, TcpStream}, path::{Path, PathBuf}, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use iced::{application, futures::{channel::{mpsc::Sender, oneshot}, executor::block_on, SinkExt}, widget::{button, checkbox, column, container, image::{Handle, Image}, pick_list, progress_bar, row, scrollable, text, text_input, Column, Row, Space}, Element, Length, Subscription, Task};
 use iced_aw::number_input;
-use openssl::ssl::{SslConnector, SslMethod, SslStream, SslVerifyMode};
-use nas_rs::{ArchivedDirEnum, ArchivedFileRead, DirEnum, FileRead, Request, StructStream};
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslStream, SslVerifyMode};
+use nas_rs::{compress::{self, Compression}, crypto::Key, locks::LockInfo, search::SearchKind, trash::{ArchivedTrashList, TrashItem, TrashList}, versions::{ArchivedVersionList, VersionInfo, VersionList}, ArchivedChange, ArchivedDirEnum, ArchivedFileInfo, ArchivedFileRead, Change, DirEnum, FileInfo, FileRead, Request, StructStream, WATCH_KEEPALIVE};
+use profiles::{Profile, Profiles};
+use queue::{bytes, Job, Queue, Status, MAX_PARALLEL};
 use rancor::{Error, Source};
 
 #[derive(Debug, Clone, PartialEq, Eq)]
 struct DirEntry {
     name: String,
     is_dir: bool,
+    /// of the plain contents, zero for directories
+    len: u64,
+    /// nanoseconds since the unix epoch
+    modified: u64,
+    /// inside a directory
+    entries: u64,
+    locks: Vec<LockInfo>,
+}
+impl DirEntry {
+    /// "folder", or the lowercased extension of a file
+    fn kind(&self) -> String {
+        match self.name.rsplit_once('.') {
+            _ if self.is_dir => "folder".to_string(),
+            Some((stem, extension)) if !stem.is_empty() => extension.to_lowercase(),
+            _ => "file".to_string(),
+        }
+    }
+}
+
+/// The columns the directory can be sorted by.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum SortBy {
+    Name,
+    Size,
+    Modified,
+    Type,
+}
+
+/// Sorts the entries by the column, directories first either way.
+fn sort(dir: &mut [DirEntry], by: SortBy, descending: bool) {
+    dir.sort_by(|a, b| {
+        let order = match by {
+            SortBy::Name => a.name.cmp(&b.name),
+            // directories by how much is in them
+            SortBy::Size => (a.len, a.entries).cmp(&(b.len, b.entries)),
+            SortBy::Modified => a.modified.cmp(&b.modified),
+            SortBy::Type => a.kind().cmp(&b.kind()),
+        }.then_with(|| a.name.cmp(&b.name));
+        b.is_dir.cmp(&a.is_dir).then(if descending { order.reverse() } else { order })
+    });
+}
+
+/// Where to connect and how, made from a [`Profile`].
+#[derive(Clone)]
+pub struct Server {
+    pub address: SocketAddrV4,
+    /// checked against the server's certificate
+    host: String,
+    connector: SslConnector,
+}
+impl std::fmt::Debug for Server {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Server").field("address", &self.address).field("host", &self.host).finish_non_exhaustive()
+    }
+}
+impl Server {
+    fn new(profile: &Profile) -> Result<Self, Error> {
+        let address = profile.address().map_err(Error::new)?;
+        let mut ssl = SslConnector::builder(SslMethod::tls_client()).map_err(Error::new)?;
+        ssl.set_verify(SslVerifyMode::PEER);
+        ssl.set_ca_file(&profile.ca_file).map_err(Error::new)?;
+        if let Some((cert, key)) = &profile.cert {
+            ssl.set_certificate_chain_file(cert).map_err(Error::new)?;
+            ssl.set_private_key_file(key, SslFiletype::PEM).map_err(Error::new)?;
+            ssl.check_private_key().map_err(Error::new)?;
+        }
+        Ok(Self { address, host: profile.host.clone(), connector: ssl.build() })
+    }
+
+    fn connect(&self) -> Result<StructStream<SslStream<TcpStream>>, Error> {
+        let tcp = TcpStream::connect(self.address).map_err(Error::new)?;
+        let stream = self.connector.connect(&self.host, tcp).map_err(Error::new)?;
+        Ok(StructStream::new(stream))
+    }
 }
 
-fn get_stream(address: SocketAddrV4) -> Result<StructStream<SslStream<TcpStream>>, Error> {
-    let tcp = TcpStream::connect(address).map_err(Error::new)?;
-    let mut ssl = SslConnector::builder(SslMethod::tls_client()).map_err(Error::new)?;
-    ssl.set_verify(SslVerifyMode::PEER);
-    ssl.set_ca_file("CA.cert").map_err(Error::new)?;
-    let ssl = ssl.build();
-    let stream = ssl.connect(&address.ip().to_string(), tcp).map_err(Error::new)?;
-    Ok(StructStream::new(stream))
+/// The path as stored on the server.
+fn remote_path(key: Option<&Key>, path: &str) -> Result<String, Error> {
+    match key {
+        Some(key) => key.encrypt_path(path).map_err(Error::new),
+        None => Ok(path.to_string()),
+    }
+}
+
+/// Called with the bytes sent or received so far and the total, an error
+/// stops the transfer.
+type OnProgress<'a> = &'a mut dyn FnMut(u64, u64) -> std::io::Result<()>;
+
+/// bytes sent or received between progress reports
+const CHUNK_LEN: usize = 64 * 1024;
+
+fn receive_buffer(stream: &mut StructStream<SslStream<TcpStream>>, len: u64, progress: OnProgress) -> Result<Vec<u8>, Error> {
+    let mut buf = vec![0; len as usize];
+    let mut done = 0;
+    progress(0, len).map_err(Error::new)?;
+    for chunk in buf.chunks_mut(CHUNK_LEN) {
+        stream.inner.read_exact(chunk).map_err(Error::new)?;
+        done += chunk.len() as u64;
+        progress(done, len).map_err(Error::new)?;
+    }
+    Ok(buf)
+}
+fn write_buffer(stream: &mut StructStream<SslStream<TcpStream>>, buf: &[u8], progress: OnProgress) -> Result<(), Error> {
+    let mut done = 0;
+    progress(0, buf.len() as u64).map_err(Error::new)?;
+    for chunk in buf.chunks(CHUNK_LEN) {
+        stream.inner.write_all(chunk).map_err(Error::new)?;
+        done += chunk.len() as u64;
+        progress(done, buf.len() as u64).map_err(Error::new)?;
+    }
+    Ok(())
 }
 
-fn enumerate(address: SocketAddrV4, path: String) -> Result<Vec<DirEntry>, Error> {
-    let request = Request::EnumDir { path };
-    let mut stream = get_stream(address)?;
+fn enumerate(server: &Server, path: &str, key: Option<&Key>) -> Result<Vec<DirEntry>, Error> {
+    let request = Request::EnumDir { path: remote_path(key, path)? };
+    let mut stream = server.connect()?;
 
     stream.write_struct::<Error>(&request)?;
 
-    let files = stream.receive_struct::<DirEnum, ArchivedDirEnum, Error>().expect("couldn't receive dir enum");
+    let files = stream.receive_struct::<DirEnum, ArchivedDirEnum, Error>()?;
     stream.receive_u64::<Error>()?;
-    let files = files.files.into_iter().map(|(path, is_dir)| DirEntry { name: path, is_dir });
+    let files = files.files.into_iter().map(|x| DirEntry {
+        name: key.map(|key| key.decrypt_name(&x.name)).unwrap_or(x.name),
+        is_dir: x.is_dir,
+        len: if key.is_some() && !x.is_dir { x.len.saturating_sub(nas_rs::crypto::OVERHEAD) } else { x.len },
+        modified: x.modified,
+        entries: x.entries,
+        locks: x.locks,
+    });
     Ok(files.collect())
 }
-fn download(address: SocketAddrV4, path: String, outpath: &Path) -> Result<(), Error> {
-    let request = Request::Read { path };
-    let mut stream = get_stream(address)?;
+/// Saves the current contents of the file, or an earlier version of it, at
+/// `out`, relative to the downloads directory. Returns where it was saved.
+fn download(server: &Server, path: &str, version: Option<u64>, out: &Path, compression: Compression, key: Option<&Key>, progress: OnProgress) -> Result<PathBuf, Error> {
+    let buf = read(server, path, version, compression, key, progress)?;
+    let downloads = std::env::current_dir().map_err(Error::new)?.join("downloads");
+    let outpath = downloads.join(out);
+    std::fs::write(&outpath, buf).map_err(Error::new)?;
+    Ok(outpath)
+}
+/// Makes the folder at `dir` on the server and all folders below it in
+/// `local`, and returns the downloads of the files in them.
+fn download_folder(server: &Server, dir: &str, local: &Path, key: Option<&Key>) -> Result<Vec<Job>, Error> {
+    let local = local.join(dir.rsplit('/').next().unwrap_or(dir));
+    std::fs::create_dir_all(&local).map_err(Error::new)?;
+    let mut jobs = vec![];
+    for file in search(server, dir, String::new(), key)? {
+        // search results come with the whole path
+        let relative = match dir {
+            "." => file.name.as_str(),
+            _ => file.name.strip_prefix(dir).unwrap_or(&file.name).trim_start_matches('/'),
+        };
+        let path = local.join(relative);
+        if file.is_dir {
+            std::fs::create_dir_all(&path).map_err(Error::new)?;
+            continue;
+        }
+        let name = path.to_str().ok_or_else(|| Error::new(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} isn't valid utf-8", path.display()))))?;
+        jobs.push(Job::Download { remote: file.name.clone(), version: None, name: name.to_string(), modified: Some(file.modified) });
+    }
+    Ok(jobs)
+}
+/// The contents of the file, or an earlier version of it, without saving them anywhere.
+fn read(server: &Server, path: &str, version: Option<u64>, compression: Compression, key: Option<&Key>, progress: OnProgress) -> Result<Vec<u8>, Error> {
+    let path = remote_path(key, path)?;
+    let request = match version {
+        Some(version) => Request::ReadVersion { path, version, accept: vec![compression] },
+        None => Request::Read { path, accept: vec![compression] },
+    };
+    let mut stream = server.connect()?;
 
     stream.write_struct::<Error>(&request)?;
 
-    let file = stream.receive_struct::<FileRead, ArchivedFileRead, Error>().expect("couldn't receive dir enum");
+    let file = stream.receive_struct::<FileRead, ArchivedFileRead, Error>()?;
+    let buf = receive_buffer(&mut stream, file.len, progress)?;
+    stream.receive_u64::<Error>()?;
+    let buf = compress::decompress(&buf, file.compression).map_err(Error::new)?;
+    match key {
+        Some(key) => key.decrypt(&buf).map_err(Error::new),
+        None => Ok(buf),
+    }
+}
+/// A png of the image made by the server, at most `size` pixels wide and high.
+fn thumbnail(server: &Server, path: &str, size: u32, key: Option<&Key>) -> Result<Vec<u8>, Error> {
+    let mut stream = server.connect()?;
+    stream.write_struct::<Error>(&Request::Thumbnail { path: remote_path(key, path)?, size })?;
+    let file = stream.receive_struct::<FileRead, ArchivedFileRead, Error>()?;
     let buf = stream.receive_buffer(file.len)?;
     stream.receive_u64::<Error>()?;
-    std::fs::write(outpath, buf).map_err(Error::new)?;
-    open::that(outpath.parent().unwrap()).map_err(Error::new)?;
-    Ok(())
+    compress::decompress(&buf, file.compression).map_err(Error::new)
 }
-fn upload(address: SocketAddrV4, path: String, inpath: &Path) -> Result<(), Error> {
+fn upload(server: &Server, path: &str, inpath: &Path, compression: Compression, key: Option<&Key>, progress: OnProgress) -> Result<(), Error> {
     let buf = std::fs::read(inpath).map_err(Error::new)?;
-    let request = Request::Write { path, len: buf.len() as u64 };
-    let mut stream = get_stream(address)?;
+    let buf = match key {
+        Some(key) => key.encrypt(&buf).map_err(Error::new)?,
+        None => buf,
+    };
+    let (compression, buf) = compress::encode(&buf, compression).map_err(Error::new)?;
+    let request = Request::Write { path: remote_path(key, path)?, len: buf.len() as u64, compression };
+    let mut stream = server.connect()?;
 
     stream.write_struct::<Error>(&request)?;
-    stream.write_buffer(&buf)?;
+    write_buffer(&mut stream, &buf, progress)?;
 
     stream.receive_u64::<Error>()?;
     Ok(())
 }
-fn delete(address: SocketAddrV4, path: String) -> Result<(), Error> {
-    let request = Request::Delete { path };
-    let mut stream = get_stream(address)?;
+fn delete(server: &Server, path: &str, key: Option<&Key>) -> Result<(), Error> {
+    simple_request(server, &Request::Delete { path: remote_path(key, path)? })
+}
+fn list_versions(server: &Server, path: &str, key: Option<&Key>) -> Result<Vec<VersionInfo>, Error> {
+    let request = Request::ListVersions { path: remote_path(key, path)? };
+    let mut stream = server.connect()?;
 
     stream.write_struct::<Error>(&request)?;
-    Ok(())
+
+    let list = stream.receive_struct::<VersionList, ArchivedVersionList, Error>()?;
+    stream.receive_u64::<Error>()?;
+    Ok(list.versions)
+}
+fn restore_version(server: &Server, path: &str, version: u64, key: Option<&Key>) -> Result<(), Error> {
+    simple_request(server, &Request::RestoreVersion { path: remote_path(key, path)?, version })
 }
-fn mkdir(address: SocketAddrV4, path: String) -> Result<(), Error> {
-    let request = Request::MkDir { path };
-    let mut stream = get_stream(address)?;
+fn list_trash(server: &Server, key: Option<&Key>) -> Result<Vec<TrashItem>, Error> {
+    let mut stream = server.connect()?;
 
+    stream.write_struct::<Error>(&Request::ListTrash)?;
+
+    let list = stream.receive_struct::<TrashList, ArchivedTrashList, Error>()?;
+    stream.receive_u64::<Error>()?;
+    Ok(list.items.into_iter().map(|mut x| {
+        if let Some(key) = key {
+            x.path = x.path.split('/').map(|x| key.decrypt_name(x)).collect::<Vec<_>>().join("/");
+        }
+        x
+    }).collect())
+}
+/// Everything below `root` whose name matches `pattern`, see [`nas_rs::search::Query`].
+fn search(server: &Server, root: &str, pattern: String, key: Option<&Key>) -> Result<Vec<FileInfo>, Error> {
+    // encrypted names can only be matched once they're decrypted here
+    let local = key.is_some_and(|x| x.encrypts_names());
+    let query = nas_rs::search::Query { pattern, ..Default::default() };
+    let request = Request::Search {
+        root: remote_path(key, root)?,
+        pattern: if local { String::new() } else { query.pattern.clone() },
+        kind: SearchKind::Any,
+        min_size: None,
+        max_size: None,
+        modified_after: None,
+    };
+    let mut stream = server.connect()?;
     stream.write_struct::<Error>(&request)?;
+    let mut results = vec![];
+    while stream.receive_u64::<Error>()? != 0 {
+        let mut file = stream.receive_struct::<FileInfo, ArchivedFileInfo, Error>()?;
+        if let Some(key) = key {
+            file.name = file.name.split('/').map(|x| key.decrypt_name(x)).collect::<Vec<_>>().join("/");
+            if !file.is_dir {
+                file.len = file.len.saturating_sub(nas_rs::crypto::OVERHEAD);
+            }
+        }
+        if !local || query.matches(&file) {
+            results.push(file);
+        }
+    }
+    Ok(results)
+}
+/// Sends a request that only answers with the final status.
+fn simple_request(server: &Server, request: &Request) -> Result<(), Error> {
+    let mut stream = server.connect()?;
+
+    stream.write_struct::<Error>(request)?;
+    stream.receive_u64::<Error>()?;
+    Ok(())
+}
+fn mkdir(server: &Server, path: &str, key: Option<&Key>) -> Result<(), Error> {
+    simple_request(server, &Request::MkDir { path: remote_path(key, path)? })
+}
+/// Makes the directories of the local folder below `dir` on the server,
+/// parents first, and returns the uploads of the files in them.
+fn upload_folder(server: &Server, local: &Path, dir: &str, key: Option<&Key>) -> Result<Vec<Job>, Error> {
+    let utf8 = |x: &Path| x.to_str().map(str::to_string).ok_or_else(|| Error::new(std::io::Error::new(std::io::ErrorKind::InvalidData, format!("{} isn't valid utf-8", x.display()))));
+    let name = utf8(Path::new(local.file_name().unwrap_or(local.as_os_str())))?;
+    let mut jobs = vec![];
+    let mut stack = vec![(local.to_path_buf(), join_path(dir, &name))];
+    while let Some((local, remote)) = stack.pop() {
+        if let Err(err) = mkdir(server, &remote, key) {
+            // uploading into a folder that's there already is fine
+            let (parent, name) = remote.rsplit_once('/').unwrap_or((".", &remote));
+            if !enumerate(server, parent, key)?.iter().any(|x| x.is_dir && x.name == name) {
+                return Err(err);
+            }
+        }
+        for entry in std::fs::read_dir(&local).map_err(Error::new)? {
+            let entry = entry.map_err(Error::new)?;
+            let path = entry.path();
+            let remote = join_path(&remote, &utf8(Path::new(&entry.file_name()))?);
+            // links to folders aren't followed, they could lead in circles
+            if entry.file_type().map_err(Error::new)?.is_dir() {
+                stack.push((path, remote));
+            } else if path.is_file() {
+                jobs.push(Job::Upload { local: utf8(&path)?, remote });
+            }
+        }
+    }
+    Ok(jobs)
+}
+
+/// Queues the upload of a local file into `dir`, or of a whole folder once
+/// its directories are there.
+fn upload_path(server: &Server, local: PathBuf, dir: &str, key: Option<Key>) -> Task<Message> {
+    if local.is_dir() {
+        let (server, dir) = (server.clone(), dir.to_string());
+        return background(move || upload_folder(&server, &local, &dir, key.as_ref()).map_err(|x| x.to_string())).map(Message::Enqueue);
+    }
+    let jobs = match (local.to_str(), local.file_name().and_then(|x| x.to_str())) {
+        (Some(path), Some(name)) => Ok(vec![Job::Upload { local: path.to_string(), remote: join_path(dir, name) }]),
+        _ => Err(format!("{} isn't valid utf-8", local.display())),
+    };
+    Task::done(Message::Enqueue(jobs))
+}
+
+/// What to do with the selected entries of a directory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Batch {
+    Download,
+    Delete,
+    Move,
+    Copy,
+}
+impl Batch {
+    /// Whether it needs confirming first.
+    fn is_destructive(self) -> bool {
+        matches!(self, Batch::Delete | Batch::Move)
+    }
+}
+impl std::fmt::Display for Batch {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Batch::Download => "Download",
+            Batch::Delete => "Delete",
+            Batch::Move => "Move",
+            Batch::Copy => "Copy",
+        })
+    }
+}
+
+/// Sends a request for each of `names` in `dir`, made from its path and the
+/// path it would have in `target`, stopping at the first that fails.
+fn run_batch(server: &Server, dir: &str, names: &[String], target: &str, key: Option<&Key>, request: fn(String, String) -> Request) -> Result<(), Error> {
+    for name in names {
+        let path = remote_path(key, &join_path(dir, name))?;
+        let to = remote_path(key, &join_path(target, name))?;
+        simple_request(server, &request(path, to))?;
+    }
     Ok(())
 }
 
+/// Sends [`Message::Changed`] for every change in the directory until the
+/// server stops or nobody listens anymore.
+fn watch(server: &Server, path: String, mut output: Sender<Message>) -> Result<(), Error> {
+    let mut stream = server.connect()?;
+    stream.write_struct::<Error>(&Request::Watch { path, recursive: false })?;
+    loop {
+        match stream.receive_u64::<Error>()? {
+            0 => return Ok(()),
+            WATCH_KEEPALIVE => {},
+            _ => {
+                stream.receive_struct::<Change, ArchivedChange, Error>()?;
+                if block_on(output.send(Message::Changed)).is_err() {
+                    return Ok(());
+                }
+            },
+        }
+        if output.is_closed() {
+            return Ok(());
+        }
+    }
+}
+
+/// Runs `f` on a thread of its own, the network calls block and would hold
+/// up the window otherwise.
+fn background<T: Send + 'static>(f: impl FnOnce() -> T + Send + 'static) -> Task<T> {
+    let (sender, receiver) = oneshot::channel();
+    std::thread::spawn(move || {
+        let _ = sender.send(f());
+    });
+    // the sender only goes away unsent if `f` panicked
+    Task::future(receiver).and_then(Task::done)
+}
+
+/// What the preview pane shows.
+#[derive(Debug, Clone)]
+enum Preview {
+    Image(Handle),
+    Text(String),
+}
+
+/// size of the thumbnails in the grid
+const THUMBNAIL_SIZE: u32 = 128;
+/// size of images in the preview pane
+const PREVIEW_SIZE: u32 = 512;
+/// text files are only previewed up to this many bytes
+const PREVIEW_LEN: usize = 64 * 1024;
+const GRID_COLUMNS: usize = 5;
+/// room for the checkboxes in front of the directory entries
+const CHECKBOX_WIDTH: f32 = 30.0;
+
+/// Whether the server can likely make a thumbnail of the file.
+fn is_image(name: &str) -> bool {
+    let extension = name.rsplit_once('.').map(|(_, x)| x.to_lowercase()).unwrap_or_default();
+    ["png", "jpg", "jpeg", "gif", "webp", "bmp"].contains(&extension.as_str())
+}
+
+fn preview(server: &Server, path: &str, compression: Compression, key: Option<&Key>) -> Preview {
+    if is_image(path) {
+        return match thumbnail(server, path, PREVIEW_SIZE, key) {
+            Ok(png) => Preview::Image(Handle::from_bytes(png)),
+            Err(_) => Preview::Text("No preview available".to_string()),
+        };
+    }
+    match read(server, path, None, compression, key, &mut |_, _| Ok(())) {
+        Ok(buf) if !buf[..buf.len().min(PREVIEW_LEN)].contains(&0) => {
+            Preview::Text(String::from_utf8_lossy(&buf[..buf.len().min(PREVIEW_LEN)]).into_owned())
+        },
+        _ => Preview::Text("No preview available".to_string()),
+    }
+}
+
+// there's only ever one, boxing the open state would buy nothing
+#[allow(clippy::large_enum_variant)]
 #[derive(Debug)]
 enum State {
     Login {
-        ip: String,
-        bad_ip: bool,
-        port: u16,
+        profiles: Profiles,
+        /// being edited, the one connected with
+        profile: Profile,
+        /// never saved with a profile
+        passphrase: String,
+        error: Option<String>,
     },
     Open {
-        socket: SocketAddrV4,
+        server: Server,
+        compression: Compression,
+        key: Option<Key>,
         path: String,
         needs_update: bool,
         dir: Vec<DirEntry>,
+        sort_by: SortBy,
+        descending: bool,
+        /// directories left, the last one most recently
+        back: Vec<String>,
+        /// directories gone back from
+        forward: Vec<String>,
         mkdir_text: String,
+        /// names of the entries picked for a batch
+        selected: BTreeSet<String>,
+        /// entry checked last, shift-clicks select everything from there
+        anchor: Option<String>,
+        shift: bool,
+        /// where a batch moves or copies to
+        target_text: String,
+        /// batch waiting for the go ahead
+        confirm: Option<Batch>,
+        search_text: String,
+        /// shows the directory as a grid of thumbnails instead of a list
+        grid: bool,
+        /// of the images in the directory by name, for the grid
+        thumbnails: HashMap<String, Handle>,
+        /// file shown next to the directory
+        preview: Option<(String, Preview)>,
+        /// search results shown instead of the directory
+        results: Option<Vec<FileInfo>>,
+        /// file whose versions are shown instead of the directory
+        history: Option<(String, Vec<VersionInfo>)>,
+        /// shown instead of the directory when open
+        trash: Option<Vec<TrashItem>>,
+        /// emptying the trash waits for the go ahead
+        confirm_empty: bool,
+        queue: Queue,
+        /// of the last request that failed, until dismissed
+        error: Option<String>,
     },
 }
 impl Default for State {
     fn default() -> Self {
-        Self::Login { ip: String::new(), bad_ip: false, port: nas_rs::PORT }
+        let profiles = Profiles::load();
+        // picks up where the last session left off
+        let profile = profiles.recent.first().cloned().unwrap_or_default();
+        Self::Login { profiles, profile, passphrase: String::new(), error: None }
     }
 }
 
-#[derive(Clone, Debug, PartialEq, Eq)]
+#[derive(Clone, Debug)]
 enum Message {
-    IpInput(String),
+    NameInput(String),
+    HostInput(String),
     PortInput(u16),
+    /// directory opened after connecting
+    PathInput(String),
+    CompressionSelect(Compression),
+    PassphraseInput(String),
+    PickKeyfile,
+    EncryptNames(bool),
+    PickCaFile,
+    /// asks for a client certificate and then its key, cancelling either goes without
+    PickCert,
+    SelectProfile(Profile),
+    SaveProfile,
+    DeleteProfile,
+    /// connects to one of the recent connections again
+    QuickConnect(usize),
     Connect,
     Open(String),
     Delete(String),
     Download(String),
+    /// asks where to and downloads the folder with everything in it
+    DownloadFolder(String),
+    /// picks files to upload
     Upload,
+    UploadFolder,
+    /// a file or folder was dropped on the window
+    Dropped(PathBuf),
+    /// uploads ready to be queued
+    Enqueue(Result<Vec<Job>, String>),
     MkdirType(String),
     Mkdir,
+    History(String),
+    CloseHistory,
+    DownloadVersion(u64),
+    RestoreVersion(u64),
+    ShowTrash,
+    CloseTrash,
+    RestoreTrash(u64),
+    /// asks before anything is deleted
+    EmptyTrash,
+    ConfirmEmptyTrash,
+    SearchType(String),
+    Search,
+    CloseSearch,
+    /// opens a directory by its whole path, from a breadcrumb or a search result
+    GoTo(String),
+    Back,
+    Forward,
+    /// lists the directory again
+    Refresh,
+    /// sorts the directory by the column, or the other way round if it already is
+    Sort(SortBy),
+    ToggleGrid,
+    Preview(String),
+    ClosePreview,
+    /// something changed in the open directory
+    Changed,
+    /// the entries of a directory arrived
+    Listed(String, Result<Vec<DirEntry>, String>),
+    /// of an image in a directory, by directory and name
+    Thumbnail(String, String, Handle),
+    Versions(String, Result<Vec<VersionInfo>, String>),
+    TrashListed(Result<Vec<TrashItem>, String>),
+    Found(Result<Vec<FileInfo>, String>),
+    Previewed(String, Preview),
+    /// a request changing the directory went through or failed
+    Done(Result<(), String>),
+    Progress { id: u64, done: u64, total: u64 },
+    Finished(u64, Result<(), String>),
+    /// a transfer waited long enough after failing
+    Retry(u64),
+    PauseTransfer(u64),
+    ResumeTransfer(u64),
+    /// stops a transfer and takes it off the queue
+    RemoveTransfer(u64),
+    /// sets how many transfers run at once
+    Parallel(u32),
+    DismissError,
+    Modifiers(iced::keyboard::Modifiers),
+    /// checks or unchecks the entry at the index, and those up to it with shift held
+    Select(usize, bool),
+    ClearSelection,
+    TargetType(String),
+    /// does something with the selected entries, after asking for destructive ones
+    Batch(Batch),
+    Confirm(Batch),
+    CancelConfirm,
 }
 
 fn update(state: &mut State, msg: Message) -> iced::Task<Message> {
     match state {
-        State::Login { ip: str_ip, bad_ip, port } => {
+        State::Login { profiles, profile, passphrase, error } => {
             match msg {
-                Message::IpInput(new_ip) => {
-                    *str_ip = new_ip;
-                    *bad_ip = false;
+                Message::NameInput(new) => profile.name = new,
+                Message::HostInput(new) => profile.host = new,
+                Message::PortInput(new) => profile.port = new,
+                Message::PathInput(new) => profile.path = new,
+                Message::CompressionSelect(new) => profile.compression = new,
+                Message::PassphraseInput(new) => *passphrase = new,
+                Message::PickKeyfile => {
+                    // a passphrase's keyfile only holds its salt and is made when it's missing
+                    let dialog = rfd::FileDialog::new().set_title("Choose a keyfile");
+                    let keyfile = match passphrase.is_empty() {
+                        true => dialog.pick_file(),
+                        false => dialog.save_file(),
+                    };
+                    profile.keyfile = keyfile.map(|x| x.display().to_string());
                 },
-                Message::PortInput(new_port) => *port = new_port,
-                Message::Connect => {
-                    let ip = Ipv4Addr::from_str(str_ip);
-                    if ip.is_err() {
-                        *bad_ip = true;
-                        str_ip.clear();
+                Message::EncryptNames(new) => profile.encrypt_names = new,
+                Message::PickCaFile => {
+                    if let Some(file) = rfd::FileDialog::new().set_title("Choose the CA certificate").pick_file() {
+                        profile.ca_file = file.display().to_string();
+                    }
+                },
+                Message::PickCert => {
+                    profile.cert = rfd::FileDialog::new().set_title("Choose a client certificate").pick_file()
+                        .and_then(|cert| Some((cert, rfd::FileDialog::new().set_title("Choose its private key").pick_file()?)))
+                        .map(|(cert, key)| (cert.display().to_string(), key.display().to_string()));
+                },
+                Message::SelectProfile(new) => *profile = new,
+                Message::SaveProfile => {
+                    if profile.name.is_empty() {
+                        *error = Some("the profile needs a name".to_string());
                         return Task::none();
                     }
-                    let ip = ip.unwrap();
-                    *state = State::Open { socket: SocketAddrV4::new(ip, *port), path: '.'.to_string(), needs_update: true, dir: vec![], mkdir_text: String::new() };
+                    profiles.insert(profile.clone());
+                    *error = profiles.save().err().map(|x| format!("saving profiles failed: {x}"));
+                },
+                Message::DeleteProfile => {
+                    profiles.remove(&profile.name);
+                    *error = profiles.save().err().map(|x| format!("saving profiles failed: {x}"));
+                },
+                Message::QuickConnect(i) => {
+                    if let Some(recent) = profiles.recent.get(i) {
+                        *profile = recent.clone();
+                        return update(state, Message::Connect);
+                    }
+                },
+                Message::Connect => {
+                    let key = match (&profile.keyfile, passphrase.is_empty()) {
+                        (Some(keyfile), true) => match Key::from_keyfile(Path::new(keyfile), profile.encrypt_names) {
+                            Ok(key) => Some(key),
+                            Err(x) => {
+                                *error = Some(format!("invalid keyfile: {x}"));
+                                return Task::none();
+                            },
+                        },
+                        (Some(keyfile), false) => match Key::from_passphrase(passphrase, Path::new(keyfile), profile.encrypt_names) {
+                            Ok(key) => Some(key),
+                            Err(x) => {
+                                *error = Some(format!("invalid passphrase: {x}"));
+                                return Task::none();
+                            },
+                        },
+                        (None, false) => {
+                            *error = Some("a passphrase needs a keyfile to keep its salt in".to_string());
+                            return Task::none();
+                        },
+                        (None, true) => None,
+                    };
+                    let server = match Server::new(profile) {
+                        Ok(server) => server,
+                        Err(x) => {
+                            *error = Some(format!("can't connect to {}: {x}", profile.host));
+                            return Task::none();
+                        },
+                    };
+                    profiles.connected(profile);
+                    // not being able to remember it is no reason not to connect
+                    let _ = profiles.save();
+                    let queue = Queue::load(server.address);
+                    *state = State::Open { server, compression: profile.compression, key, path: profile.path.clone(), needs_update: true, dir: vec![], sort_by: SortBy::Name, descending: false, back: vec![], forward: vec![], mkdir_text: String::new(), selected: BTreeSet::new(), anchor: None, shift: false, target_text: String::new(), confirm: None, search_text: String::new(), grid: false, thumbnails: HashMap::new(), preview: None, results: None, history: None, trash: None, confirm_empty: false, queue, error: None };
                     return update(state, msg);
                 },
                 _ => panic!("invalid message")
             }
         },
-        State::Open { path, socket, needs_update, dir, mkdir_text } => {
+        State::Open { path, server: open_server, compression, key: open_key, needs_update, dir, sort_by, descending, back, forward, mkdir_text, selected, anchor, shift, target_text, confirm, search_text, grid, thumbnails, preview: preview_pane, results, history, trash, confirm_empty, queue, error } => {
+            let (server, compression, key) = (open_server.clone(), *compression, open_key.clone());
+            let mut task = Task::none();
+            // the messages that change what's queued
+            let saves_queue = matches!(msg, Message::Download(_) | Message::Enqueue(_) | Message::DownloadVersion(_) | Message::Finished(..)
+                | Message::PauseTransfer(_) | Message::ResumeTransfer(_) | Message::RemoveTransfer(_) | Message::Parallel(_));
             match msg {
                 Message::Open(open) => {
-                    if open == ".." {
-                        if path.contains('/') {
-                            *path = path.rsplit_once('/').unwrap().0.to_string()
-                        } else {
-                            *path = '.'.to_string()
+                    let to = if open == ".." {
+                        match path.rsplit_once('/') {
+                            Some((parent, _)) => parent.to_string(),
+                            None => '.'.to_string(),
                         }
-                    } else if path == "." {
-                        *path = open;
                     } else {
-                        path.push('/');
-                        path.push_str(&open);
+                        join_path(path, &open)
+                    };
+                    if to != *path {
+                        back.push(std::mem::replace(path, to));
+                        forward.clear();
                     }
+                    selected.clear();
+                    *anchor = None;
                     *needs_update = true;
                 },
+                Message::Back | Message::Forward => {
+                    let (from, to) = if matches!(msg, Message::Back) { (back, forward) } else { (forward, back) };
+                    if let Some(dir) = from.pop() {
+                        to.push(std::mem::replace(path, dir));
+                        selected.clear();
+                        *anchor = None;
+                        *needs_update = true;
+                    }
+                },
+                Message::Refresh => *needs_update = true,
+                Message::Sort(by) => {
+                    // the same column again turns the order around
+                    *descending = by == *sort_by && !*descending;
+                    *sort_by = by;
+                    sort(dir, *sort_by, *descending);
+                },
                 Message::Download(file_name) => {
-                    let absolute_path = if path != "." {
-                        let mut absolute_path = path.clone();
-                        absolute_path.push('/');
-                        absolute_path.push_str(&file_name);
-                        absolute_path
-                    } else {
-                        file_name.clone()
-                    };
-                    let mut outpath = std::env::current_dir().unwrap();
-                    outpath.push("downloads");
-                    outpath.push(file_name);
-                    download(*socket, absolute_path, &outpath).unwrap();
+                    queue.push(Job::Download { remote: join_path(path, &file_name), version: None, name: file_name, modified: None });
+                    task = queue.schedule(&server, compression, key.as_ref());
+                },
+                Message::DownloadFolder(name) => {
+                    if let Some(local) = rfd::FileDialog::new().set_title("Download to").pick_folder() {
+                        let dir = join_path(path, &name);
+                        task = background(move || download_folder(&server, &dir, &local, key.as_ref()).map_err(|x| x.to_string())).map(Message::Enqueue);
+                    }
                 },
                 Message::Upload => {
-                    let file_path = if let Some(file_path) = open_file() {
-                        file_path
-                    } else {
+                    task = Task::batch(pick_files().into_iter().map(|x| upload_path(&server, x, path, key.clone())));
+                },
+                Message::UploadFolder => {
+                    if let Some(folder) = rfd::FileDialog::new().set_title("Upload a folder").pick_folder() {
+                        task = upload_path(&server, folder, path, key);
+                    }
+                },
+                Message::Dropped(local) => task = upload_path(&server, local, path, key),
+                Message::Enqueue(jobs) => match jobs {
+                    Ok(jobs) => {
+                        for job in jobs {
+                            queue.push(job);
+                        }
+                        task = queue.schedule(&server, compression, key.as_ref());
+                    },
+                    Err(x) => *error = Some(x),
+                },
+                Message::Delete(file_name) => {
+                    let absolute_path = join_path(path, &file_name);
+                    task = background(move || delete(&server, &absolute_path, key.as_ref()).map_err(|x| x.to_string())).map(Message::Done);
+                },
+                Message::Mkdir => {
+                    let absolute_path = join_path(path, mkdir_text);
+                    task = background(move || mkdir(&server, &absolute_path, key.as_ref()).map_err(|x| x.to_string())).map(Message::Done);
+                }
+                Message::MkdirType(new) => {
+                    *mkdir_text = new;
+                },
+                Message::History(file_name) => {
+                    let absolute_path = join_path(path, &file_name);
+                    task = background(move || list_versions(&server, &absolute_path, key.as_ref()).map_err(|x| x.to_string()))
+                        .map(move |x| Message::Versions(file_name.clone(), x));
+                },
+                Message::Versions(file_name, versions) => match versions {
+                    Ok(versions) => *history = Some((file_name, versions)),
+                    Err(x) => *error = Some(x),
+                },
+                Message::CloseHistory => *history = None,
+                Message::DownloadVersion(version) => {
+                    let Some((file_name, _)) = history.as_ref() else {
                         return Task::none();
                     };
-
-                    let absolute_path = if path != "." {
-                        let mut absolute_path = path.clone();
-                        absolute_path.push('/');
-                        absolute_path.push_str(file_path.file_name().unwrap().try_into().unwrap());
-                        absolute_path
-                    } else {
-                        TryInto::<&str>::try_into(file_path.file_name().unwrap()).unwrap().to_owned()
+                    queue.push(Job::Download { remote: join_path(path, file_name), version: Some(version), name: format!("{version}-{file_name}"), modified: None });
+                    task = queue.schedule(&server, compression, key.as_ref());
+                },
+                Message::RestoreVersion(version) => {
+                    let Some((file_name, _)) = history.as_ref() else {
+                        return Task::none();
                     };
-                    upload(*socket, absolute_path, &file_path).unwrap();
+                    let (file_name, absolute_path) = (file_name.clone(), join_path(path, file_name));
+                    task = background(move || {
+                        restore_version(&server, &absolute_path, version, key.as_ref())?;
+                        list_versions(&server, &absolute_path, key.as_ref())
+                    }).map(move |x| Message::Versions(file_name.clone(), x.map_err(|x| x.to_string())))
+                        .chain(Task::done(Message::Changed));
+                },
+                Message::ShowTrash => {
+                    task = background(move || list_trash(&server, key.as_ref()).map_err(|x| x.to_string())).map(Message::TrashListed);
+                },
+                Message::TrashListed(items) => match items {
+                    Ok(items) => *trash = Some(items),
+                    Err(x) => *error = Some(x),
+                },
+                Message::CloseTrash => {
+                    *trash = None;
+                    *confirm_empty = false;
+                },
+                Message::RestoreTrash(id) => {
+                    task = background(move || {
+                        simple_request(&server, &Request::RestoreTrash { id })?;
+                        list_trash(&server, key.as_ref())
+                    }).map(|x| Message::TrashListed(x.map_err(|x| x.to_string())))
+                        .chain(Task::done(Message::Changed));
+                },
+                Message::EmptyTrash => *confirm_empty = true,
+                Message::ConfirmEmptyTrash => {
+                    *confirm_empty = false;
+                    task = background(move || {
+                        simple_request(&server, &Request::EmptyTrash)?;
+                        list_trash(&server, key.as_ref())
+                    }).map(|x| Message::TrashListed(x.map_err(|x| x.to_string())));
+                },
+                Message::SearchType(new) => *search_text = new,
+                Message::Search => {
+                    let (root, pattern) = (path.clone(), search_text.clone());
+                    task = background(move || search(&server, &root, pattern, key.as_ref()).map_err(|x| x.to_string())).map(Message::Found);
+                },
+                Message::Found(found) => match found {
+                    Ok(found) => *results = Some(found),
+                    Err(x) => *error = Some(x),
+                },
+                Message::CloseSearch => *results = None,
+                Message::GoTo(dir) => {
+                    if dir != *path {
+                        back.push(std::mem::replace(path, dir));
+                        forward.clear();
+                    }
+                    *results = None;
+                    selected.clear();
+                    *anchor = None;
                     *needs_update = true;
-                }
-                Message::Delete(file_name) => {
-                    let absolute_path = if path != "." {
-                        let mut absolute_path = path.clone();
-                        absolute_path.push('/');
-                        absolute_path.push_str(&file_name);
-                        absolute_path
-                    } else {
-                        file_name.clone()
-                    };
-                    println!("{absolute_path}");
-                    delete(*socket, absolute_path).unwrap();
+                },
+                Message::ToggleGrid => {
+                    *grid = !*grid;
                     *needs_update = true;
                 },
-                Message::Mkdir => {
-                    let absolute_path = if path != "." {
-                        let mut absolute_path = path.clone();
-                        absolute_path.push('/');
-                        absolute_path.push_str(mkdir_text);
-                        absolute_path
-                    } else {
-                        mkdir_text.clone()
+                Message::Preview(file_name) => {
+                    let absolute_path = join_path(path, &file_name);
+                    task = background(move || preview(&server, &absolute_path, compression, key.as_ref()))
+                        .map(move |x| Message::Previewed(file_name.clone(), x));
+                },
+                Message::Previewed(file_name, content) => *preview_pane = Some((file_name, content)),
+                Message::ClosePreview => *preview_pane = None,
+                Message::Changed => *needs_update = true,
+                Message::Listed(listed, entries) => {
+                    // an answer for a directory that's no longer open
+                    if listed != *path {
+                        return Task::none();
+                    }
+                    match entries {
+                        Ok(entries) => *dir = entries,
+                        Err(x) => *error = Some(x),
+                    }
+                    sort(dir, *sort_by, *descending);
+                    selected.retain(|x| dir.iter().any(|entry| entry.name == *x));
+                    thumbnails.clear();
+                    if *grid {
+                        task = Task::batch(dir.iter().filter(|x| !x.is_dir && is_image(&x.name)).map(|entry| {
+                            let (server, dir, name, key) = (server.clone(), path.clone(), entry.name.clone(), key.clone());
+                            let absolute_path = join_path(path, &entry.name);
+                            // files the server can't read as images just go without
+                            background(move || thumbnail(&server, &absolute_path, THUMBNAIL_SIZE, key.as_ref()).ok())
+                                .and_then(move |png| Task::done(Message::Thumbnail(dir.clone(), name.clone(), Handle::from_bytes(png))))
+                        }));
+                    }
+                },
+                Message::Thumbnail(dir, name, handle) => {
+                    if dir == *path {
+                        thumbnails.insert(name, handle);
+                    }
+                },
+                Message::Done(result) => match result {
+                    Ok(()) => *needs_update = true,
+                    Err(x) => *error = Some(x),
+                },
+                Message::Progress { id, done, total } => queue.progress(id, done, total),
+                Message::Finished(id, result) => {
+                    let uploaded = result.is_ok() && queue.get(id).is_some_and(|x| matches!(x.job, Job::Upload { .. }));
+                    let downloads = std::env::current_dir().map(|x| x.join("downloads"));
+                    let downloaded = result.is_ok() && queue.get(id).is_some_and(|x| match &x.job {
+                        Job::Download { name, .. } => Path::new(name).is_relative() || downloads.as_ref().is_ok_and(|x| Path::new(name).starts_with(x)),
+                        Job::Upload { .. } => false,
+                    });
+                    *needs_update |= uploaded;
+                    task = Task::batch([queue.finished(id, result), queue.schedule(&server, compression, key.as_ref())]);
+                    // shown once the last of them is in, not for every file
+                    if downloaded && !queue.downloading() && let Err(x) = downloads.and_then(open::that) {
+                        *error = Some(format!("couldn't open the downloads folder: {x}"));
+                    }
+                },
+                Message::Retry(id) => {
+                    queue.retry(id);
+                    task = queue.schedule(&server, compression, key.as_ref());
+                },
+                Message::PauseTransfer(id) => {
+                    queue.pause(id);
+                    task = queue.schedule(&server, compression, key.as_ref());
+                },
+                Message::ResumeTransfer(id) => {
+                    queue.resume(id);
+                    task = queue.schedule(&server, compression, key.as_ref());
+                },
+                Message::RemoveTransfer(id) => {
+                    queue.remove(id);
+                    task = queue.schedule(&server, compression, key.as_ref());
+                },
+                Message::Parallel(parallel) => {
+                    queue.parallel = parallel;
+                    task = queue.schedule(&server, compression, key.as_ref());
+                },
+                Message::DismissError => *error = None,
+                Message::Modifiers(modifiers) => *shift = modifiers.shift(),
+                Message::Select(i, checked) => {
+                    // by name, the listing may have changed since
+                    let start = anchor.as_ref().filter(|_| *shift).and_then(|x| dir.iter().position(|entry| entry.name == *x));
+                    let range = match start {
+                        Some(start) => start.min(i)..=start.max(i),
+                        None => i..=i,
                     };
-                    println!("{absolute_path}");
-                    mkdir(*socket, absolute_path).unwrap();
-                    *needs_update = true;
-                }
-                Message::MkdirType(new) => {
-                    *mkdir_text = new;
+                    for entry in dir.iter().skip(*range.start()).take(range.end() - range.start() + 1) {
+                        if checked {
+                            selected.insert(entry.name.clone());
+                        } else {
+                            selected.remove(&entry.name);
+                        }
+                    }
+                    *anchor = dir.get(i).map(|x| x.name.clone());
+                },
+                Message::ClearSelection => {
+                    selected.clear();
+                    *anchor = None;
+                },
+                Message::TargetType(new) => *target_text = new,
+                Message::Batch(Batch::Download) => {
+                    let (folders, files): (Vec<_>, Vec<_>) = dir.iter().filter(|x| selected.contains(&x.name)).partition(|x| x.is_dir);
+                    for entry in files {
+                        queue.push(Job::Download { remote: join_path(path, &entry.name), version: None, name: entry.name.clone(), modified: None });
+                    }
+                    // folders end up in the downloads directory too
+                    let folders = folders.iter().map(|x| join_path(path, &x.name)).collect::<Vec<_>>();
+                    let folders = Task::batch(folders.into_iter().map(|dir| {
+                        let (server, key) = (server.clone(), key.clone());
+                        background(move || {
+                            let local = std::env::current_dir().map_err(Error::new)?.join("downloads");
+                            download_folder(&server, &dir, &local, key.as_ref())
+                        }).map(|x| Message::Enqueue(x.map_err(|x| x.to_string())))
+                    }));
+                    task = Task::batch([queue.schedule(&server, compression, key.as_ref()), folders]);
+                },
+                Message::Batch(batch) if batch.is_destructive() => *confirm = Some(batch),
+                Message::Batch(batch) | Message::Confirm(batch) => {
+                    *confirm = None;
+                    let request: fn(String, String) -> Request = match batch {
+                        Batch::Delete => |path, _| Request::Delete { path },
+                        Batch::Move => |path, to| Request::Rename { path, to },
+                        Batch::Copy => |path, to| Request::Copy { path, to },
+                        // queued above
+                        Batch::Download => return Task::none(),
+                    };
+                    let (server, dir, names, target) = (server.clone(), path.clone(), selected.iter().cloned().collect::<Vec<_>>(), target_text.clone());
+                    if batch != Batch::Copy {
+                        selected.clear();
+                        *anchor = None;
+                    }
+                    task = background(move || run_batch(&server, &dir, &names, &target, key.as_ref(), request).map_err(|x| x.to_string())).map(Message::Done);
                 },
-                Message::Connect => {},
+                Message::CancelConfirm => {
+                    *confirm = None;
+                    *confirm_empty = false;
+                },
+                // transfers left over from last time start again
+                Message::Connect => task = queue.schedule(&server, compression, key.as_ref()),
                 _ => {
                     panic!("invalid message");
                 }
             }
+            if saves_queue && let Err(x) = queue.save() {
+                *error = Some(format!("couldn't save the transfers: {x}"));
+            }
             if *needs_update {
-                *dir = enumerate(*socket, path.clone()).unwrap();
                 *needs_update = false;
+                let listed = path.clone();
+                let (server, dir_path, key) = (open_server.clone(), path.clone(), open_key.clone());
+                let list = background(move || enumerate(&server, &dir_path, key.as_ref()).map_err(|x| x.to_string()))
+                    .map(move |x| Message::Listed(listed.clone(), x));
+                return Task::batch([task, list]);
             }
+            return task;
         },
     }
     Task::none()
 }
-fn view(state: &State) -> iced::Element<Message> {
+/// Keeps the open directory up to date with changes made elsewhere and
+/// uploads what's dropped on the window.
+fn subscription(state: &State) -> Subscription<Message> {
+    let State::Open { server, key, path, .. } = state else {
+        return Subscription::none();
+    };
+    let dropped = iced::event::listen_with(|event, _, _| match event {
+        iced::Event::Window(iced::window::Event::FileDropped(path)) => Some(Message::Dropped(path)),
+        iced::Event::Keyboard(iced::keyboard::Event::ModifiersChanged(modifiers)) => Some(Message::Modifiers(modifiers)),
+        _ => None,
+    });
+    let Ok(path) = remote_path(key.as_ref(), path) else {
+        return dropped;
+    };
+    let server = server.clone();
+    let changes = Subscription::run_with_id((server.address, path.clone()), iced::stream::channel(16, move |output| async move {
+        // the connection blocks, so it gets a thread of its own
+        std::thread::spawn(move || watch(&server, path, output));
+        std::future::pending::<()>().await
+    }));
+    Subscription::batch([changes, dropped])
+}
+/// The open page with the last error above it and the transfers below.
+fn view(state: &State) -> iced::Element<'_, Message> {
+    let State::Open { queue, error, .. } = state else {
+        return page(state);
+    };
+    let error = error.as_ref().map(|x| {
+        row!(
+            text(format!("Error: {x}")).width(Length::Fill),
+            button(text("dismiss")).on_press(Message::DismissError),
+        ).spacing(5).align_y(iced::Alignment::Center)
+    });
+    let transfers = queue.transfers.iter().map(|x| {
+        let fraction = if x.total > 0 { x.done as f32 / x.total as f32 } else { 0.0 };
+        let toggle = match x.status {
+            Status::Queued | Status::Running(_) | Status::Waiting(_) => button(text("pause")).on_press(Message::PauseTransfer(x.id)),
+            Status::Paused => button(text("resume")).on_press(Message::ResumeTransfer(x.id)),
+            Status::Failed(_) => button(text("retry")).on_press(Message::ResumeTransfer(x.id)),
+        };
+        row!(
+            text(x.job.label()).width(Length::FillPortion(1)),
+            progress_bar(0.0..=1.0, fraction).height(Length::Fixed(10.0)).width(Length::FillPortion(2)),
+            text(x.status()).width(Length::FillPortion(2)),
+            toggle,
+            button(text("remove")).on_press(Message::RemoveTransfer(x.id)),
+        ).spacing(5).align_y(iced::Alignment::Center).into()
+    });
+    let header = row!(
+        text(format!("Transfers ({})", queue.transfers.len())).width(Length::Fill),
+        text("at once "),
+        number_input(&queue.parallel, 1..=MAX_PARALLEL, Message::Parallel).width(Length::Fixed(60.0)),
+    ).align_y(iced::Alignment::Center);
+    column!(
+        container(page(state)).height(Length::Fill),
+        header,
+        scrollable(Column::from_iter(transfers).spacing(5)).height(Length::Shrink),
+    ).push_maybe(error).spacing(5).into()
+}
+fn page(state: &State) -> iced::Element<'_, Message> {
     match state {
-        State::Login { ip, port, bad_ip } => {
+        State::Login { profiles, profile, passphrase, error } => {
+            let saved = profiles.saved.iter().any(|x| x.name == profile.name);
+            let recent = profiles.recent.iter().enumerate().map(|(i, x)| {
+                button(text(x.to_string())).on_press(Message::QuickConnect(i)).width(Length::Fill).into()
+            });
             container(
                 column!(
-                    text_input(if *bad_ip {"Invalid Ip"} else {"Ipv4 address"}, ip).on_input(Message::IpInput),
-                    vertical_space().height(Length::Fixed(5.0)),
-                    number_input(port, 0..=u16::MAX, Message::PortInput).ignore_buttons(true).width(Length::Fill),
-                    vertical_space().height(Length::Fixed(5.0)),
-                    button(text("Connect")).on_press(Message::Connect).width(Length::Fill)
-                ).max_width(250).height(Length::Shrink)
+                    pick_list(profiles.saved.as_slice(), profiles.saved.iter().find(|x| *x == profile), Message::SelectProfile)
+                        .placeholder("Saved profiles").width(Length::Fill),
+                    text_input("Profile name", &profile.name).on_input(Message::NameInput),
+                    text_input("Host name or ipv4 address", &profile.host).on_input(Message::HostInput),
+                    number_input(&profile.port, 0..=u16::MAX, Message::PortInput).ignore_buttons(true).width(Length::Fill),
+                    text_input("Directory to open", &profile.path).on_input(Message::PathInput),
+                    button(text(format!("CA certificate: {}", profile.ca_file))).on_press(Message::PickCaFile).width(Length::Fill),
+                    button(text(match &profile.cert {
+                        Some((cert, _)) => format!("Client certificate: {cert}"),
+                        None => "Choose client certificate (optional)".to_string(),
+                    })).on_press(Message::PickCert).width(Length::Fill),
+                    row!(
+                        text("Compression "),
+                        pick_list(Compression::ALL, Some(profile.compression), Message::CompressionSelect).width(Length::Fill),
+                    ).align_y(iced::Alignment::Center),
+                    text_input("Encryption passphrase (optional)", passphrase).secure(true).on_input(Message::PassphraseInput),
+                    button(text(match &profile.keyfile {
+                        Some(keyfile) => format!("Keyfile: {keyfile}"),
+                        None => "Choose keyfile (optional)".to_string(),
+                    })).on_press(Message::PickKeyfile).width(Length::Fill),
+                    checkbox("Encrypt file names", profile.encrypt_names).on_toggle(Message::EncryptNames),
+                    row!(
+                        button(text("Save profile")).on_press(Message::SaveProfile).width(Length::Fill),
+                        button(text("Delete profile")).on_press_maybe(saved.then_some(Message::DeleteProfile)).width(Length::Fill),
+                    ).spacing(5),
+                    button(text("Connect")).on_press(Message::Connect).width(Length::Fill),
+                ).push_maybe(error.as_ref().map(text))
+                .push_maybe((!profiles.recent.is_empty()).then(|| text("Recent connections")))
+                .extend(recent)
+                .spacing(5).max_width(300).height(Length::Shrink)
             ).center(Length::Fill).into()
         },
-        State::Open { path, dir, mkdir_text, .. } => {
-            let elems = dir.iter().map(|x| {
-                let item = if x.is_dir {
-                    button(text(x.name.clone())).on_press(Message::Open(x.name.clone()))    
+        State::Open { results: Some(results), search_text, .. } => {
+            let elems = results.iter().map(|x| {
+                let dir = if x.is_dir {
+                    x.name.clone()
                 } else {
-                    button(text(x.name.clone())).on_press(Message::Download(x.name.clone()))
+                    x.name.rsplit_once('/').map(|(dir, _)| dir.to_string()).unwrap_or_else(|| ".".to_string())
                 };
                 row!(
-                    item,
-                    button(text("delete")).on_press(Message::Delete(x.name.clone()))
-                ).into()
+                    text(if x.is_dir { format!("{}/", x.name) } else { format!("{}, {} bytes", x.name, x.len) }),
+                    button(text("show")).on_press(Message::GoTo(dir)),
+                ).spacing(5).align_y(iced::Alignment::Center).into()
             });
             column!(
-                text(path),
+                text(format!("Results for \"{search_text}\"")),
+                button(text("back")).on_press(Message::CloseSearch),
+                if results.is_empty() { text("Nothing found") } else { text("") },
+                Column::from_iter(elems)
+            ).into()
+        },
+        State::Open { trash: Some(items), confirm_empty, .. } => {
+            let elems = items.iter().map(|x| {
                 row!(
-                    button(text("upload")).on_press_with(|| {Message::Upload}),
-                    text_input("New Folder Name", mkdir_text).on_input(Message::MkdirType).on_submit(Message::Mkdir),
+                    text(format!("{}{}, deleted {} ago", x.path, if x.is_dir { "/" } else { "" }, age(x.id))),
+                    button(text("restore")).on_press(Message::RestoreTrash(x.id)),
+                ).spacing(5).align_y(iced::Alignment::Center).into()
+            });
+            let buttons = match confirm_empty {
+                true => row!(
+                    text(format!("Permanently delete the {} entries in the trash?", items.len())),
+                    button(text("empty trash")).on_press(Message::ConfirmEmptyTrash),
+                    button(text("cancel")).on_press(Message::CancelConfirm),
+                ).spacing(5).align_y(iced::Alignment::Center),
+                false => row!(
+                    button(text("back")).on_press(Message::CloseTrash),
+                    button(text("empty trash")).on_press_maybe((!items.is_empty()).then_some(Message::EmptyTrash)),
                 ),
-                button(text("..")).on_press(Message::Open("..".to_string())),
+            };
+            column!(
+                text("Trash"),
+                buttons,
+                if items.is_empty() { text("Trash is empty") } else { text("") },
+                Column::from_iter(elems)
+            ).into()
+        },
+        State::Open { path, history: Some((file_name, versions)), .. } => {
+            let elems = versions.iter().map(|x| {
+                row!(
+                    text(format!("{} ago, {} bytes", age(x.id), x.len)),
+                    button(text("download")).on_press(Message::DownloadVersion(x.id)),
+                    button(text("restore")).on_press(Message::RestoreVersion(x.id)),
+                ).spacing(5).align_y(iced::Alignment::Center).into()
+            });
+            column!(
+                text(format!("History of {}", join_path(path, file_name))),
+                button(text("back")).on_press(Message::CloseHistory),
+                if versions.is_empty() { text("No earlier versions") } else { text("") },
                 Column::from_iter(elems)
             ).into()
         },
+        State::Open { path, selected, target_text, confirm: Some(batch), .. } => {
+            let elems = selected.iter().map(|x| text(join_path(path, x)).into());
+            let question = match batch {
+                Batch::Move => format!("Move these {} entries to {target_text}?", selected.len()),
+                _ => format!("{} these {} entries?", batch, selected.len()),
+            };
+            column!(
+                text(question),
+                row!(
+                    button(text(batch.to_string())).on_press(Message::Confirm(*batch)),
+                    button(text("cancel")).on_press(Message::CancelConfirm),
+                ).spacing(5),
+                scrollable(Column::from_iter(elems)),
+            ).spacing(5).into()
+        },
+        State::Open { path, dir, sort_by, descending, back, forward, mkdir_text, selected, target_text, search_text, grid, thumbnails, preview, .. } => {
+            let entries: Element<_> = if *grid {
+                let mut cells = dir.iter().map(|x| {
+                    let picture: Element<_> = match thumbnails.get(&x.name) {
+                        Some(handle) => Image::new(handle.clone()).into(),
+                        None => text(if x.is_dir { "folder" } else { "file" }).into(),
+                    };
+                    let open = if x.is_dir { Message::Open(x.name.clone()) } else { Message::Preview(x.name.clone()) };
+                    column!(
+                        container(picture).center(THUMBNAIL_SIZE as f32),
+                        button(text(x.name.clone())).on_press(open),
+                    ).width(THUMBNAIL_SIZE as f32 + 20.0).into()
+                }).peekable();
+                let mut rows = Column::new().spacing(10);
+                while cells.peek().is_some() {
+                    rows = rows.push(Row::from_iter(cells.by_ref().take(GRID_COLUMNS)).spacing(10));
+                }
+                rows.into()
+            } else {
+                let header = [(SortBy::Name, "Name", 3), (SortBy::Size, "Size", 1), (SortBy::Modified, "Modified", 2), (SortBy::Type, "Type", 1)]
+                    .into_iter()
+                    .fold(row!(Space::with_width(CHECKBOX_WIDTH)), |header, (by, name, portion)| {
+                        let arrow = match (by == *sort_by, *descending) {
+                            (false, _) => "",
+                            (true, false) => " ▲",
+                            (true, true) => " ▼",
+                        };
+                        header.push(button(text(format!("{name}{arrow}"))).on_press(Message::Sort(by)).style(button::text).width(Length::FillPortion(portion)))
+                    });
+                let rows = dir.iter().enumerate().map(|(i, x)| {
+                    let item = if x.is_dir {
+                        button(text(x.name.clone())).on_press(Message::Open(x.name.clone()))
+                    } else {
+                        button(text(x.name.clone())).on_press(Message::Download(x.name.clone()))
+                    };
+                    let size = match (x.is_dir, x.entries) {
+                        (false, _) => bytes(x.len),
+                        (true, 1) => "1 item".to_string(),
+                        (true, entries) => format!("{entries} items"),
+                    };
+                    let preview = match x.is_dir {
+                        true => button(text("download")).on_press(Message::DownloadFolder(x.name.clone())),
+                        false => button(text("preview")).on_press(Message::Preview(x.name.clone())),
+                    };
+                    let history = (!x.is_dir).then(|| button(text("history")).on_press(Message::History(x.name.clone())));
+                    let locks = (!x.locks.is_empty()).then(|| text(lock_holders(&x.locks)));
+                    row!(
+                        container(checkbox("", selected.contains(&x.name)).on_toggle(move |checked| Message::Select(i, checked))).width(CHECKBOX_WIDTH),
+                        item.width(Length::FillPortion(3)),
+                        text(size).width(Length::FillPortion(1)),
+                        text(format!("{} ago", age(x.modified))).width(Length::FillPortion(2)),
+                        text(x.kind()).width(Length::FillPortion(1)),
+                        button(text("delete")).on_press(Message::Delete(x.name.clone())),
+                    ).align_y(iced::Alignment::Center).push(preview).push_maybe(history).push_maybe(locks).into()
+                });
+                column!(header).extend(rows).into()
+            };
+            // every directory on the way down, each opening with a click
+            let mut crumbs = row!(button(text("root")).on_press_maybe((path != ".").then(|| Message::GoTo('.'.to_string()))).style(button::text));
+            if path != "." {
+                let parts: Vec<_> = path.split('/').collect();
+                for (i, name) in parts.iter().enumerate() {
+                    let to = parts[..=i].join("/");
+                    let open = (i + 1 < parts.len()).then_some(Message::GoTo(to));
+                    crumbs = crumbs.push(text("/")).push(button(text(*name)).on_press_maybe(open).style(button::text));
+                }
+            }
+            let listing = column!(
+                row!(
+                    button(text("back")).on_press_maybe((!back.is_empty()).then_some(Message::Back)),
+                    button(text("forward")).on_press_maybe((!forward.is_empty()).then_some(Message::Forward)),
+                    button(text("up")).on_press_maybe((path != ".").then(|| Message::Open("..".to_string()))),
+                    button(text("refresh")).on_press(Message::Refresh),
+                    crumbs.align_y(iced::Alignment::Center),
+                ).spacing(5).align_y(iced::Alignment::Center),
+                row!(
+                    button(text("upload")).on_press_with(|| {Message::Upload}),
+                    button(text("upload folder")).on_press(Message::UploadFolder),
+                    button(text("trash")).on_press(Message::ShowTrash),
+                    button(text(if *grid { "list" } else { "grid" })).on_press(Message::ToggleGrid),
+                    text_input("New Folder Name", mkdir_text).on_input(Message::MkdirType).on_submit(Message::Mkdir),
+                    text_input("Search", search_text).on_input(Message::SearchType).on_submit(Message::Search),
+                ),
+            ).push_maybe((!selected.is_empty()).then(|| {
+                let target = (!target_text.is_empty()).then_some(());
+                row!(
+                    text(format!("{} selected ", selected.len())),
+                    button(text("download")).on_press(Message::Batch(Batch::Download)),
+                    button(text("delete")).on_press(Message::Batch(Batch::Delete)),
+                    text_input("Move or copy to", target_text).on_input(Message::TargetType),
+                    button(text("move")).on_press_maybe(target.map(|_| Message::Batch(Batch::Move))),
+                    button(text("copy")).on_press_maybe(target.map(|_| Message::Batch(Batch::Copy))),
+                    button(text("clear")).on_press(Message::ClearSelection),
+                ).align_y(iced::Alignment::Center)
+            })).push(scrollable(entries));
+            let pane = preview.as_ref().map(|(file_name, content)| {
+                let content: Element<_> = match content {
+                    Preview::Image(handle) => Image::new(handle.clone()).into(),
+                    Preview::Text(content) => scrollable(text(content.as_str())).into(),
+                };
+                column!(
+                    text(file_name.as_str()),
+                    row!(
+                        button(text("download")).on_press(Message::Download(file_name.clone())),
+                        button(text("close")).on_press(Message::ClosePreview),
+                    ),
+                    content,
+                ).width(Length::FillPortion(1))
+            });
+            row!(listing.width(Length::FillPortion(2))).push_maybe(pane).spacing(10).into()
+        },
+    }
+}
+
+/// "locked by alice" or "locked by alice, bob (shared)"
+fn lock_holders(locks: &[LockInfo]) -> String {
+    let owners = locks.iter().map(|x| x.owner.as_str()).collect::<Vec<_>>().join(", ");
+    if locks.iter().any(|x| x.exclusive) {
+        format!("locked by {owners}")
+    } else {
+        format!("locked by {owners} (shared)")
+    }
+}
+
+fn join_path(dir: &str, name: &str) -> String {
+    if dir == "." {
+        name.to_string()
+    } else {
+        format!("{dir}/{name}")
+    }
+}
+
+/// How long ago `id`, in nanoseconds since the unix epoch, was, roughly.
+fn age(id: u64) -> String {
+    let then = UNIX_EPOCH + Duration::from_nanos(id);
+    let secs = SystemTime::now().duration_since(then).unwrap_or_default().as_secs();
+    match secs {
+        0..60 => format!("{secs}s"),
+        60..3600 => format!("{}min", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{} days", secs / 86400),
     }
 }
 
-fn open_file() -> Option<PathBuf> {
-    rfd::FileDialog::new().set_title("Upload a file").pick_file()
+fn pick_files() -> Vec<PathBuf> {
+    rfd::FileDialog::new().set_title("Upload files").pick_files().unwrap_or_default()
 }
 
 fn main() {
@@ -272,6 +1333,6 @@ fn main() {
         }
     }
     
-    let app = application::<State, Message, _, _>("nas_rs client", update, view);
+    let app = application::<State, Message, _, _>("nas_rs client", update, view).subscription(subscription);
     app.run().unwrap();
 }
@@ -0,0 +1,116 @@
+//! Named connection settings and the servers connected to lately, kept in
+//! [`PROFILES_FILE`] in the user's config directory.
+
+use std::{fmt, io, net::{SocketAddr, SocketAddrV4, ToSocketAddrs}, path::PathBuf};
+
+use nas_rs::compress::Compression;
+use rancor::{Error, Source};
+use rkyv::{util::AlignedVec, Archive, Deserialize, Serialize};
+
+/// inside the user's config directory
+pub const PROFILES_FILE: &str = "nas_rs/profiles";
+/// connections remembered for quick connect
+pub const MAX_RECENT: usize = 5;
+
+#[derive(Serialize, Deserialize, Archive, Clone, Debug, PartialEq, Eq)]
+pub struct Profile {
+    /// empty for connections that were never saved
+    pub name: String,
+    /// ipv4 address or host name, checked against the server's certificate
+    pub host: String,
+    pub port: u16,
+    pub ca_file: String,
+    /// client certificate and its private key, both pem
+    pub cert: Option<(String, String)>,
+    /// directory opened after connecting
+    pub path: String,
+    pub compression: Compression,
+    pub keyfile: Option<String>,
+    pub encrypt_names: bool,
+}
+impl Default for Profile {
+    fn default() -> Self {
+        Self {
+            name: String::new(),
+            host: String::new(),
+            port: nas_rs::PORT,
+            ca_file: "CA.cert".to_string(),
+            cert: None,
+            path: ".".to_string(),
+            compression: Compression::None,
+            keyfile: None,
+            encrypt_names: false,
+        }
+    }
+}
+impl Profile {
+    /// The address to connect to, looking up host names.
+    pub fn address(&self) -> io::Result<SocketAddrV4> {
+        (self.host.as_str(), self.port).to_socket_addrs()?
+            .find_map(|x| match x {
+                SocketAddr::V4(x) => Some(x),
+                SocketAddr::V6(_) => None,
+            })
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} has no ipv4 address", self.host)))
+    }
+}
+/// for the profile picker
+impl fmt::Display for Profile {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.name.is_empty() {
+            true => write!(f, "{}:{}", self.host, self.port),
+            false => write!(f, "{} ({}:{})", self.name, self.host, self.port),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Archive, Default, Debug)]
+pub struct Profiles {
+    /// by name
+    pub saved: Vec<Profile>,
+    /// newest first
+    pub recent: Vec<Profile>,
+}
+impl Profiles {
+    fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|x| x.join(PROFILES_FILE))
+    }
+
+    /// What's saved, nothing if there's no config directory or the file is
+    /// missing or broken.
+    pub fn load() -> Self {
+        let Some(bytes) = Self::path().and_then(|x| std::fs::read(x).ok()) else {
+            return Self::default();
+        };
+        let mut aligned = AlignedVec::<16>::with_capacity(bytes.len());
+        aligned.extend_from_slice(&bytes);
+        rkyv::from_bytes::<Self, Error>(&aligned).unwrap_or_default()
+    }
+
+    pub fn save(&self) -> Result<(), Error> {
+        let path = Self::path().ok_or_else(|| Error::new(io::Error::new(io::ErrorKind::NotFound, "there's no config directory")))?;
+        if let Some(dir) = path.parent() {
+            std::fs::create_dir_all(dir).map_err(Error::new)?;
+        }
+        let bytes = rkyv::to_bytes::<Error>(self)?;
+        std::fs::write(path, bytes).map_err(Error::new)
+    }
+
+    /// Adds the profile, replacing one with the same name.
+    pub fn insert(&mut self, profile: Profile) {
+        self.remove(&profile.name);
+        self.saved.push(profile);
+        self.saved.sort_by(|a, b| a.name.cmp(&b.name));
+    }
+
+    pub fn remove(&mut self, name: &str) {
+        self.saved.retain(|x| x.name != name);
+    }
+
+    /// Puts the profile at the top of the recent connections.
+    pub fn connected(&mut self, profile: &Profile) {
+        self.recent.retain(|x| x != profile);
+        self.recent.insert(0, profile.clone());
+        self.recent.truncate(MAX_RECENT);
+    }
+}
@@ -0,0 +1,307 @@
+//! The uploads and downloads of the GUI, run a few at a time in the
+//! background and kept in [`QUEUE_PATH`] so they survive a restart.
+//!
+//! A failed transfer is tried again after a while, waiting twice as long
+//! every time, until it failed [`MAX_ATTEMPTS`] times. Paused or interrupted
+//! transfers start over from the beginning when they're resumed.
+
+use std::{fs::File, net::SocketAddrV4, path::Path, sync::{atomic::{AtomicBool, AtomicU64, Ordering}, Arc}, time::{Duration, Instant, UNIX_EPOCH}};
+
+use iced::{futures::channel::mpsc, Task};
+use nas_rs::{compress::Compression, crypto::Key};
+use rancor::{Error, Source};
+use rkyv::{util::AlignedVec, Archive, Deserialize, Serialize};
+
+use crate::{background, download, upload, Message, OnProgress, Server};
+
+/// in the working directory, next to the downloads
+pub const QUEUE_PATH: &str = ".nas_rs_queue";
+/// transfers running at once unless set otherwise
+pub const DEFAULT_PARALLEL: u32 = 2;
+pub const MAX_PARALLEL: u32 = 16;
+/// a transfer that failed this often isn't tried again on its own
+pub const MAX_ATTEMPTS: u32 = 5;
+/// wait before the first retry
+const BACKOFF: Duration = Duration::from_secs(2);
+/// how often a transfer reports its progress at most
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// What a transfer moves where, all it takes to start it over.
+#[derive(Serialize, Deserialize, Archive, Clone, Debug)]
+pub enum Job {
+    /// a local file to a path on the server
+    Upload { local: String, remote: String },
+    /// An earlier version if given, saved as `name`, relative to the
+    /// downloads directory. Given the modification time, in nanoseconds
+    /// since the unix epoch, the file gets it.
+    Download { remote: String, version: Option<u64>, name: String, modified: Option<u64> },
+}
+impl Job {
+    pub fn label(&self) -> String {
+        match self {
+            Job::Upload { remote, .. } => format!("upload {remote}"),
+            Job::Download { name, .. } => format!("download {name}"),
+        }
+    }
+    fn run(&self, server: &Server, compression: Compression, key: Option<&Key>, progress: OnProgress) -> Result<(), Error> {
+        match self {
+            Job::Upload { local, remote } => upload(server, remote, Path::new(local), compression, key, progress),
+            Job::Download { remote, version, name, modified } => {
+                let path = download(server, remote, *version, Path::new(name), compression, key, progress)?;
+                if let Some(modified) = modified {
+                    let modified = UNIX_EPOCH + Duration::from_nanos(*modified);
+                    File::options().write(true).open(path).and_then(|x| x.set_modified(modified)).map_err(Error::new)?;
+                }
+                Ok(())
+            },
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum Status {
+    Queued,
+    /// with the flag that stops it
+    Running(Arc<AtomicBool>),
+    Paused,
+    /// failed with the error, tried again soon
+    Waiting(String),
+    /// failed with the error too often
+    Failed(String),
+}
+
+#[derive(Debug)]
+pub struct Transfer {
+    /// changes every time it starts, so reports from an earlier run are ignored
+    pub id: u64,
+    pub job: Job,
+    pub status: Status,
+    /// failed runs so far
+    pub attempts: u32,
+    pub done: u64,
+    pub total: u64,
+    started: Instant,
+}
+impl Transfer {
+    fn new(job: Job, paused: bool) -> Self {
+        let status = if paused { Status::Paused } else { Status::Queued };
+        Self { id: NEXT_ID.fetch_add(1, Ordering::Relaxed), job, status, attempts: 0, done: 0, total: 0, started: Instant::now() }
+    }
+
+    /// Runs the job on a thread of its own, it sends [`Message::Progress`]
+    /// now and then and [`Message::Finished`] at the end.
+    fn start(&mut self, server: Server, compression: Compression, key: Option<Key>) -> Task<Message> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let cancel = Arc::new(AtomicBool::new(false));
+        let cancelled = cancel.clone();
+        let job = self.job.clone();
+        let (sender, receiver) = mpsc::unbounded();
+        std::thread::spawn(move || {
+            let mut reported: Option<Instant> = None;
+            let result = job.run(&server, compression, key.as_ref(), &mut |done, total| {
+                if cancelled.load(Ordering::Relaxed) {
+                    return Err(std::io::Error::new(std::io::ErrorKind::Interrupted, "cancelled"));
+                }
+                if done == total || reported.is_none_or(|x| x.elapsed() >= PROGRESS_INTERVAL) {
+                    reported = Some(Instant::now());
+                    let _ = sender.unbounded_send(Message::Progress { id, done, total });
+                }
+                Ok(())
+            });
+            let _ = sender.unbounded_send(Message::Finished(id, result.map_err(|x| x.to_string())));
+        });
+        self.id = id;
+        self.status = Status::Running(cancel);
+        (self.done, self.total) = (0, 0);
+        self.started = Instant::now();
+        Task::run(receiver, std::convert::identity)
+    }
+
+    /// Stops it if it's running.
+    fn stop(&self) {
+        if let Status::Running(cancel) = &self.status {
+            cancel.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// "1.5 MiB of 4.0 MiB, 800.0 KiB/s, 3s left"
+    pub fn status(&self) -> String {
+        match &self.status {
+            Status::Queued => "queued".to_string(),
+            Status::Paused => "paused".to_string(),
+            Status::Waiting(error) => format!("failed: {error}, trying again ({} of {MAX_ATTEMPTS} attempts)", self.attempts),
+            Status::Failed(error) => format!("failed: {error}"),
+            Status::Running(_) => {
+                let secs = self.started.elapsed().as_secs_f64();
+                let speed = if secs > 0.0 { self.done as f64 / secs } else { 0.0 };
+                let left = match speed > 0.0 {
+                    true => format!("{}s left", ((self.total - self.done) as f64 / speed).ceil()),
+                    false => "starting".to_string(),
+                };
+                format!("{} of {}, {}/s, {left}", bytes(self.done), bytes(self.total), bytes(speed as u64))
+            },
+        }
+    }
+}
+
+/// `len` in the largest unit that keeps it at least 1.
+pub fn bytes(len: u64) -> String {
+    let mut len = len as f64;
+    for unit in ["B", "KiB", "MiB", "GiB"] {
+        if len < 1024.0 {
+            return if unit == "B" { format!("{len} {unit}") } else { format!("{len:.1} {unit}") };
+        }
+        len /= 1024.0;
+    }
+    format!("{len:.1} TiB")
+}
+
+/// A transfer as it's saved.
+#[derive(Serialize, Deserialize, Archive)]
+struct Saved {
+    /// address of the server it belongs to
+    server: String,
+    job: Job,
+    paused: bool,
+}
+
+#[derive(Serialize, Deserialize, Archive, Default)]
+struct SavedQueue {
+    parallel: u32,
+    transfers: Vec<Saved>,
+}
+impl SavedQueue {
+    /// What's in [`QUEUE_PATH`], nothing if it's missing or broken.
+    fn read() -> Self {
+        let Ok(bytes) = std::fs::read(QUEUE_PATH) else {
+            return Self::default();
+        };
+        let mut aligned = AlignedVec::<16>::with_capacity(bytes.len());
+        aligned.extend_from_slice(&bytes);
+        rkyv::from_bytes::<Self, Error>(&aligned).unwrap_or_default()
+    }
+}
+
+/// The transfers to and from one server.
+#[derive(Debug)]
+pub struct Queue {
+    server: SocketAddrV4,
+    /// transfers running at once
+    pub parallel: u32,
+    pub transfers: Vec<Transfer>,
+}
+impl Queue {
+    /// The transfers saved for `server`, waiting to start again.
+    pub fn load(server: SocketAddrV4) -> Self {
+        let saved = SavedQueue::read();
+        let transfers = saved.transfers.into_iter()
+            .filter(|x| x.server == server.to_string())
+            .map(|x| Transfer::new(x.job, x.paused))
+            .collect();
+        let parallel = if saved.parallel == 0 { DEFAULT_PARALLEL } else { saved.parallel };
+        Self { server, parallel, transfers }
+    }
+
+    /// Writes the queue to [`QUEUE_PATH`] along with those of other servers.
+    /// Transfers that failed for good are kept paused.
+    pub fn save(&self) -> Result<(), Error> {
+        let server = self.server.to_string();
+        let mut saved = SavedQueue::read();
+        saved.parallel = self.parallel;
+        saved.transfers.retain(|x| x.server != server);
+        saved.transfers.extend(self.transfers.iter().map(|x| Saved {
+            server: server.clone(),
+            job: x.job.clone(),
+            paused: matches!(x.status, Status::Paused | Status::Failed(_)),
+        }));
+        let bytes = rkyv::to_bytes::<Error>(&saved)?;
+        std::fs::write(QUEUE_PATH, bytes).map_err(Error::new)
+    }
+
+    pub fn push(&mut self, job: Job) {
+        self.transfers.push(Transfer::new(job, false));
+    }
+
+    /// Starts queued transfers until as many run as allowed.
+    pub fn schedule(&mut self, server: &Server, compression: Compression, key: Option<&Key>) -> Task<Message> {
+        let running = self.transfers.iter().filter(|x| matches!(x.status, Status::Running(_))).count();
+        let free = (self.parallel as usize).saturating_sub(running);
+        Task::batch(self.transfers.iter_mut()
+            .filter(|x| matches!(x.status, Status::Queued))
+            .take(free)
+            .map(|x| x.start(server.clone(), compression, key.cloned()))
+            .collect::<Vec<_>>())
+    }
+
+    /// Whether downloads are still to come, paused and failed ones aside.
+    pub fn downloading(&self) -> bool {
+        self.transfers.iter().any(|x| matches!(x.job, Job::Download { .. }) && matches!(x.status, Status::Queued | Status::Running(_) | Status::Waiting(_)))
+    }
+
+    pub fn get(&self, id: u64) -> Option<&Transfer> {
+        self.transfers.iter().find(|x| x.id == id)
+    }
+
+    pub fn progress(&mut self, id: u64, done: u64, total: u64) {
+        if let Some(transfer) = self.transfers.iter_mut().find(|x| x.id == id) {
+            transfer.done = done;
+            transfer.total = total;
+        }
+    }
+
+    /// Drops a transfer that went through. One that failed waits before
+    /// it's tried again, and the returned task sends [`Message::Retry`] then.
+    pub fn finished(&mut self, id: u64, result: Result<(), String>) -> Task<Message> {
+        let Some(i) = self.transfers.iter().position(|x| x.id == id && matches!(x.status, Status::Running(_))) else {
+            return Task::none();
+        };
+        let error = match result {
+            Ok(()) => {
+                self.transfers.remove(i);
+                return Task::none();
+            },
+            Err(error) => error,
+        };
+        let transfer = &mut self.transfers[i];
+        transfer.attempts += 1;
+        if transfer.attempts >= MAX_ATTEMPTS {
+            transfer.status = Status::Failed(error);
+            return Task::none();
+        }
+        transfer.status = Status::Waiting(error);
+        let delay = BACKOFF * 2u32.pow(transfer.attempts - 1);
+        background(move || std::thread::sleep(delay)).map(move |_| Message::Retry(id))
+    }
+
+    /// Queues a transfer waiting after a failure again.
+    pub fn retry(&mut self, id: u64) {
+        if let Some(transfer) = self.transfers.iter_mut().find(|x| x.id == id && matches!(x.status, Status::Waiting(_))) {
+            transfer.status = Status::Queued;
+        }
+    }
+
+    pub fn pause(&mut self, id: u64) {
+        if let Some(transfer) = self.transfers.iter_mut().find(|x| x.id == id) {
+            transfer.stop();
+            transfer.status = Status::Paused;
+        }
+    }
+
+    /// Queues a paused or failed transfer again, with a fresh set of attempts.
+    pub fn resume(&mut self, id: u64) {
+        if let Some(transfer) = self.transfers.iter_mut().find(|x| x.id == id && !matches!(x.status, Status::Running(_))) {
+            transfer.status = Status::Queued;
+            transfer.attempts = 0;
+        }
+    }
+
+    /// Stops the transfer if it's running and forgets it.
+    pub fn remove(&mut self, id: u64) {
+        if let Some(transfer) = self.get(id) {
+            transfer.stop();
+        }
+        self.transfers.retain(|x| x.id != id);
+    }
+}
@@ -4,7 +4,7 @@
 //! and private keys.
 //! Thank you to: https://github.com/sfackler/rust-openssl/blob/master/openssl/examples/mk_certs.rs for the code
 
-use std::fs::File;
+use std::fs::{read, File};
 use std::io::Write;
 use std::net::Ipv4Addr;
 
@@ -66,12 +66,12 @@ fn mk_ca_cert() -> Result<(X509, PKey<Private>), ErrorStack> {
 }
 
 /// Make a X509 request with the given private key
-fn mk_request(key_pair: &PKey<Private>) -> Result<X509Req, ErrorStack> {
+fn mk_request(key_pair: &PKey<Private>, common_name: &str) -> Result<X509Req, ErrorStack> {
     let mut req_builder = X509ReqBuilder::new()?;
     req_builder.set_pubkey(key_pair)?;
 
     let mut x509_name = X509NameBuilder::new()?;
-    x509_name.append_entry_by_text("CN", "Server certificate for nas_rs server")?;
+    x509_name.append_entry_by_text("CN", common_name)?;
     let x509_name = x509_name.build();
     req_builder.set_subject_name(&x509_name)?;
 
@@ -80,16 +80,18 @@ fn mk_request(key_pair: &PKey<Private>) -> Result<X509Req, ErrorStack> {
     Ok(req)
 }
 
-/// Make a certificate and private key signed by the given CA cert and private key
+/// Make a certificate and private key signed by the given CA cert and private key.
+/// Server certificates need the ip they're served on, client certificates have none.
 fn mk_ca_signed_cert(
     ca_cert: &X509Ref,
     ca_key_pair: &PKeyRef<Private>,
-    ip: Ipv4Addr,
+    common_name: &str,
+    ip: Option<Ipv4Addr>,
 ) -> Result<(X509, PKey<Private>), ErrorStack> {
     let rsa = Rsa::generate(2048)?;
     let key_pair = PKey::from_rsa(rsa)?;
 
-    let req = mk_request(&key_pair)?;
+    let req = mk_request(&key_pair, common_name)?;
 
     let mut cert_builder = X509::builder()?;
     cert_builder.set_version(2)?;
@@ -128,10 +130,12 @@ fn mk_ca_signed_cert(
         .build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
     cert_builder.append_extension(auth_key_identifier)?;
 
-    let subject_alt_name = SubjectAlternativeName::new()
-        .ip(&ip.to_string())
-        .build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
-    cert_builder.append_extension(subject_alt_name)?;
+    if let Some(ip) = ip {
+        let subject_alt_name = SubjectAlternativeName::new()
+            .ip(&ip.to_string())
+            .build(&cert_builder.x509v3_context(Some(ca_cert), None))?;
+        cert_builder.append_extension(subject_alt_name)?;
+    }
 
     cert_builder.sign(ca_key_pair, MessageDigest::sha256())?;
     let cert = cert_builder.build();
@@ -143,14 +147,26 @@ fn real_main() -> Result<(), ErrorStack> {
     let args= clap::Command::new("nas_rs")
         .arg(
             arg!(--ip <ip>)
-            .required(false)
+            .required_unless_present("client")
             .value_parser(value_parser!(Ipv4Addr))
+        ).arg(
+            arg!(--client <name> "sign a certificate for user <name> with the existing CA instead")
+            .required(false)
         ).get_matches();
-    
+
+    if let Some(name) = args.get_one::<String>("client") {
+        let ca_cert = X509::from_pem(&read("CA.cert").expect("no CA.cert, run without --client first"))?;
+        let ca_key_pair = PKey::private_key_from_pem(&read("CA.key").expect("no CA.key, run without --client first"))?;
+        let (cert, key_pair) = mk_ca_signed_cert(&ca_cert, &ca_key_pair, name, None)?;
+        File::create(format!("{}.cert", name)).unwrap().write_all(&cert.to_pem().unwrap()).unwrap();
+        File::create(format!("{}.key", name)).unwrap().write_all(&key_pair.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        return Ok(());
+    }
+
     let (ca_cert, ca_key_pair) = mk_ca_cert()?;
     File::create("CA.cert").unwrap().write_all(&ca_cert.to_pem().unwrap()).unwrap();
     File::create("CA.key").unwrap().write_all(&ca_key_pair.private_key_to_pem_pkcs8().unwrap()).unwrap();
-    let (cert, key_pair) = mk_ca_signed_cert(&ca_cert, &ca_key_pair, *args.get_one("ip").unwrap())?;
+    let (cert, key_pair) = mk_ca_signed_cert(&ca_cert, &ca_key_pair, "Server certificate for nas_rs server", Some(*args.get_one("ip").unwrap()))?;
     File::create("SERVER.cert").unwrap().write_all(&cert.to_pem().unwrap()).unwrap();
     File::create("SERVER.key").unwrap().write_all(&key_pair.private_key_to_pem_pkcs8().unwrap()).unwrap();
 
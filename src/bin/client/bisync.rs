@@ -0,0 +1,345 @@
+//! `client sync both`, merging changes made on either side since the last run.
+//!
+//! What both sides looked like after the last run is kept in [`STATE_FILE`] in
+//! the local root. A file counts as changed on a side when its size or
+//! modification time differs from then, and changes on both sides are a
+//! conflict settled by the [`ConflictPolicy`]. Deletions only propagate for
+//! entries the other side hasn't changed since, so nothing new is lost.
+
+use std::{collections::{BTreeMap, BTreeSet}, io::{self, BufRead, Write}, path::Path};
+
+use rkyv::{rancor::{self, Error, Source}, util::AlignedVec, Archive, Deserialize, Serialize};
+
+use crate::{remote::Remote, sync::{download, is_below, join, local_tree, nanos, remote_tree, remove_local, Entry, Tree, STATE_FILE}};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ConflictPolicy {
+    /// keeps the other side's copy next to the file with a suffix
+    KeepBoth,
+    /// the most recently modified copy wins
+    Newest,
+    /// asks on the terminal
+    Prompt,
+}
+
+pub struct Options {
+    pub conflict: ConflictPolicy,
+    /// only report what would change
+    pub dry_run: bool,
+}
+
+/// directories have none that matters
+#[derive(Serialize, Deserialize, Archive, Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Stamp {
+    len: u64,
+    modified: u64,
+}
+impl From<&Entry> for Stamp {
+    fn from(entry: &Entry) -> Self {
+        Self { len: entry.len, modified: entry.modified }
+    }
+}
+
+#[derive(Serialize, Deserialize, Archive, Clone, Copy, Debug)]
+struct Synced {
+    is_dir: bool,
+    local: Stamp,
+    remote: Stamp,
+}
+
+#[derive(Serialize, Deserialize, Archive, Default, Debug)]
+struct State {
+    /// server and directory the state was recorded against
+    remote: String,
+    synced: BTreeMap<String, Synced>,
+}
+impl State {
+    fn load(path: &Path, remote: &str) -> Result<Self, Error> {
+        let bytes = match std::fs::read(path) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self { remote: remote.to_string(), ..Default::default() }),
+            Err(err) => return Err(Error::new(err)),
+        };
+        let mut aligned = AlignedVec::<16>::with_capacity(bytes.len());
+        aligned.extend_from_slice(&bytes);
+        let state = rkyv::from_bytes::<State, rancor::Error>(&aligned)?;
+        if state.remote != remote {
+            eprintln!("{} was synced with {}, starting over", path.display(), state.remote);
+            return Ok(Self { remote: remote.to_string(), ..Default::default() });
+        }
+        Ok(state)
+    }
+    fn save(&self, path: &Path) -> Result<(), Error> {
+        let bytes = rkyv::to_bytes::<rancor::Error>(self)?;
+        let temp = path.with_extension("tmp");
+        std::fs::write(&temp, &bytes).map_err(Error::new)?;
+        std::fs::rename(temp, path).map_err(Error::new)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Step {
+    Push,
+    Pull,
+    MkdirRemote,
+    MkdirLocal,
+    DeleteRemote,
+    DeleteLocal,
+    Conflict,
+    /// a file on one side and a directory on the other
+    Mismatch,
+}
+
+/// `a/b.txt` becomes `a/b.conflict-<secs>.txt`
+fn conflict_name(path: &str, modified: u64) -> String {
+    let suffix = format!("conflict-{}", modified / 1_000_000_000);
+    let (dir, name) = match path.rsplit_once('/') {
+        Some((dir, name)) => (format!("{dir}/"), name),
+        None => (String::new(), path),
+    };
+    match name.rsplit_once('.') {
+        Some((stem, ext)) if !stem.is_empty() => format!("{dir}{stem}.{suffix}.{ext}"),
+        _ => format!("{dir}{name}.{suffix}"),
+    }
+}
+
+fn plan(local: &Tree, server: &Tree, state: &State) -> BTreeMap<String, Step> {
+    let paths: BTreeSet<&String> = local.keys().chain(server.keys()).collect();
+    let mut steps = BTreeMap::new();
+    for path in paths {
+        let synced = state.synced.get(path);
+        // directories have no contents of their own, only their existence counts
+        let changed = |entry: &Entry, stamp: fn(&Synced) -> Stamp| {
+            synced.is_none_or(|x| x.is_dir != entry.is_dir || (!entry.is_dir && stamp(x) != Stamp::from(entry)))
+        };
+        let step = match (local.get(path), server.get(path)) {
+            (None, None) => continue,
+            (Some(l), None) if synced.is_some() && !changed(l, |x| x.local) => Step::DeleteLocal,
+            (Some(l), None) => if l.is_dir { Step::MkdirRemote } else { Step::Push },
+            (None, Some(r)) if synced.is_some() && !changed(r, |x| x.remote) => Step::DeleteRemote,
+            (None, Some(r)) => if r.is_dir { Step::MkdirLocal } else { Step::Pull },
+            (Some(l), Some(r)) if l.is_dir != r.is_dir => Step::Mismatch,
+            (Some(l), Some(_)) if l.is_dir => continue,
+            (Some(l), Some(r)) => match (changed(l, |x| x.local), changed(r, |x| x.remote)) {
+                (false, false) => continue,
+                (true, false) => Step::Push,
+                (false, true) => Step::Pull,
+                (true, true) => Step::Conflict,
+            },
+        };
+        steps.insert(path.clone(), step);
+    }
+    // a directory is only deleted if everything in it would be, otherwise it's recreated
+    let dirs: Vec<_> = steps.iter()
+        .filter(|(path, step)| matches!(step, Step::DeleteLocal | Step::DeleteRemote) && local.get(*path).or(server.get(*path)).unwrap().is_dir)
+        .map(|(path, step)| (path.clone(), *step))
+        .collect();
+    for (dir, step) in dirs.into_iter().rev() {
+        if steps.iter().any(|(path, x)| *x != step && is_below(path, std::slice::from_ref(&dir))) {
+            steps.insert(dir, if step == Step::DeleteLocal { Step::MkdirRemote } else { Step::MkdirLocal });
+        }
+    }
+    steps
+}
+
+/// The stamp of a local file this client just wrote.
+fn local_stamp(path: &Path, remote: &Remote) -> Result<Stamp, Error> {
+    let metadata = std::fs::metadata(path).map_err(Error::new)?;
+    Ok(Stamp { len: remote.stored_len(metadata.len()), modified: nanos(metadata.modified().map_err(Error::new)?) })
+}
+
+/// The stamp of a file this client just wrote to the server, `None` if it
+/// isn't `len` long anymore because someone else has changed it since.
+fn remote_stamp(remote: &Remote, remote_path: &str, len: u64) -> Result<Option<Stamp>, Error> {
+    let (dir, name) = remote_path.rsplit_once('/').unwrap_or((".", remote_path));
+    Ok(remote.list(dir)?.into_iter()
+        .find(|x| x.name == name && !x.is_dir && x.len == len)
+        .map(|x| Stamp { len: x.len, modified: x.modified }))
+}
+
+/// Whether the two copies hold the same bytes, only worth checking when the sizes match.
+fn same_contents(remote: &Remote, remote_path: &str, local_path: &Path) -> Result<bool, Error> {
+    let local = std::fs::read(local_path).map_err(Error::new)?;
+    Ok(remote.read(remote_path)? == local)
+}
+
+fn ask(path: &str) -> Option<Step> {
+    loop {
+        print!("{path} changed on both sides, keep [l]ocal, [r]emote, [b]oth or [s]kip? ");
+        io::stdout().flush().ok()?;
+        let mut line = String::new();
+        if io::stdin().lock().read_line(&mut line).ok()? == 0 {
+            return None;
+        }
+        match line.trim() {
+            "l" => return Some(Step::Push),
+            "r" => return Some(Step::Pull),
+            "b" => return Some(Step::Conflict),
+            "s" => return None,
+            _ => {},
+        }
+    }
+}
+
+pub fn run(remote: &Remote, local_root: &Path, remote_root: &str, options: &Options) -> Result<(), Error> {
+    let state_path = local_root.join(STATE_FILE);
+    let remote_id = format!("{}/{}", remote.address, remote_root);
+    let mut state = State::load(&state_path, &remote_id)?;
+    let local = local_tree(local_root, remote).map_err(Error::new)?;
+    let server = remote_tree(remote_root, remote)?;
+    // a missing root would otherwise look like everything was deleted
+    if (local.is_none() || server.is_none()) && !state.synced.is_empty() {
+        return Err(Error::new(io::Error::new(io::ErrorKind::NotFound, "a directory that was synced before is gone")));
+    }
+    let (create_local, create_remote) = (local.is_none(), server.is_none());
+    let (local, server) = (local.unwrap_or_default(), server.unwrap_or_default());
+    let steps = plan(&local, &server, &state);
+    // deleting a directory takes everything below it
+    let mut deleted = vec![];
+    let steps: Vec<_> = steps.into_iter()
+        .filter(|(path, step)| {
+            if is_below(path, &deleted) {
+                return false;
+            }
+            if matches!(step, Step::DeleteLocal | Step::DeleteRemote) {
+                deleted.push(path.clone());
+            }
+            true
+        })
+        .collect();
+
+    if options.dry_run {
+        for (path, step) in &steps {
+            match step {
+                Step::Push => println!("would upload {path}"),
+                Step::Pull => println!("would download {path}"),
+                Step::MkdirRemote => println!("would create {path}/ on the server"),
+                Step::MkdirLocal => println!("would create {path}/ locally"),
+                Step::DeleteRemote => println!("would delete {path} on the server"),
+                Step::DeleteLocal => println!("would delete {path} locally"),
+                Step::Conflict => println!("conflict on {path}, changed on both sides"),
+                Step::Mismatch => println!("skipping {path}: a file on one side and a directory on the other"),
+            }
+        }
+        println!("{} change(s)", steps.len());
+        return Ok(());
+    }
+
+    if create_local {
+        std::fs::create_dir_all(local_root).map_err(Error::new)?;
+    }
+    if create_remote {
+        remote.mkdir(remote_root)?;
+    }
+    // The new state is what both sides looked like when the plan was made,
+    // with the entries transferred put in as they're done. Looking at them
+    // again afterwards would take what others changed meanwhile for synced.
+    let planned: BTreeSet<&String> = steps.iter().map(|(path, _)| path).collect();
+    let mut synced: BTreeMap<String, Synced> = local.iter()
+        .filter(|(path, _)| !planned.contains(path))
+        .filter_map(|(path, l)| {
+            let r = server.get(path).filter(|r| r.is_dir == l.is_dir)?;
+            Some((path.clone(), Synced { is_dir: l.is_dir, local: l.into(), remote: r.into() }))
+        })
+        .collect();
+    // keep what they were synced as so they're looked at again next time
+    let mut unresolved = BTreeSet::new();
+    let mut changes = 0;
+    for (path, step) in steps {
+        let (local_path, remote_path) = (local_root.join(&path), join(remote_root, &path));
+        let step = match step {
+            Step::Conflict => {
+                let (l, r) = (&local[&path], &server[&path]);
+                if l.len == r.len && same_contents(remote, &remote_path, &local_path)? {
+                    synced.insert(path, Synced { is_dir: false, local: l.into(), remote: r.into() });
+                    continue;
+                }
+                let resolved = match options.conflict {
+                    ConflictPolicy::KeepBoth => Some(Step::Conflict),
+                    ConflictPolicy::Newest => Some(if l.modified >= r.modified { Step::Push } else { Step::Pull }),
+                    ConflictPolicy::Prompt => ask(&path),
+                };
+                let Some(resolved) = resolved else {
+                    println!("skipped {path}");
+                    unresolved.insert(path);
+                    continue;
+                };
+                resolved
+            },
+            Step::Mismatch => {
+                eprintln!("skipping {path}: a file on one side and a directory on the other");
+                unresolved.insert(path);
+                continue;
+            },
+            step => step,
+        };
+        match step {
+            Step::Push => {
+                let data = std::fs::read(&local_path).map_err(Error::new)?;
+                if server.contains_key(&path) {
+                    remote.update(&remote_path, &data)?;
+                } else {
+                    remote.write(&remote_path, &data)?;
+                }
+                println!("uploaded {path}");
+                if let Some(r) = remote_stamp(remote, &remote_path, remote.stored_len(data.len() as u64))? {
+                    synced.insert(path.clone(), Synced { is_dir: false, local: (&local[&path]).into(), remote: r });
+                }
+            },
+            Step::Pull => {
+                download(remote, &remote_path, &local_path, server[&path].modified)?;
+                println!("downloaded {path}");
+                let l = local_stamp(&local_path, remote)?;
+                synced.insert(path.clone(), Synced { is_dir: false, local: l, remote: (&server[&path]).into() });
+            },
+            Step::MkdirRemote => {
+                remote.mkdir(&remote_path)?;
+                println!("created {path}/ on the server");
+                synced.insert(path.clone(), Synced { is_dir: true, local: Stamp::default(), remote: Stamp::default() });
+            },
+            Step::MkdirLocal => {
+                std::fs::create_dir(&local_path).map_err(Error::new)?;
+                println!("created {path}/ locally");
+                synced.insert(path.clone(), Synced { is_dir: true, local: Stamp::default(), remote: Stamp::default() });
+            },
+            Step::DeleteRemote => {
+                remote.delete(&remote_path)?;
+                println!("deleted {path} on the server");
+            },
+            Step::DeleteLocal => {
+                remove_local(&local_path)?;
+                println!("deleted {path} locally");
+            },
+            Step::Conflict => {
+                // both sides end up with the local copy under the name and the server's next to it
+                let copy = conflict_name(&path, server[&path].modified);
+                let (local_copy, remote_copy) = (local_root.join(&copy), join(remote_root, &copy));
+                download(remote, &remote_path, &local_copy, server[&path].modified)?;
+                let copy_data = std::fs::read(&local_copy).map_err(Error::new)?;
+                remote.write(&remote_copy, &copy_data)?;
+                let data = std::fs::read(&local_path).map_err(Error::new)?;
+                remote.update(&remote_path, &data)?;
+                println!("kept both copies of {path}, the server's as {copy}");
+                let l = local_stamp(&local_copy, remote)?;
+                if let Some(r) = remote_stamp(remote, &remote_copy, remote.stored_len(copy_data.len() as u64))? {
+                    synced.insert(copy, Synced { is_dir: false, local: l, remote: r });
+                }
+                if let Some(r) = remote_stamp(remote, &remote_path, remote.stored_len(data.len() as u64))? {
+                    synced.insert(path.clone(), Synced { is_dir: false, local: (&local[&path]).into(), remote: r });
+                }
+            },
+            Step::Mismatch => unreachable!(),
+        }
+        changes += 1;
+    }
+
+    for path in unresolved {
+        if let Some(x) = state.synced.get(&path) {
+            synced.insert(path, *x);
+        }
+    }
+    state.synced = synced;
+    state.save(&state_path)?;
+    println!("{changes} change(s)");
+    Ok(())
+}
@@ -0,0 +1,430 @@
+mod bisync;
+mod remote;
+mod sync;
+
+use std::{fs::File, io::{Read, Write}, net::{Ipv4Addr, SocketAddrV4}, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use clap::{arg, value_parser, Command};
+use nas_rs::{compress::{self, Compression}, crypto::Key, index::{ArchivedContentHit, ContentHit}, locks::{ArchivedLockInfo, LockInfo}, search::{Query, SearchKind}, trash::{ArchivedTrashList, TrashList}, versions::{ArchivedVersionList, VersionList}, ArchivedChange, ArchivedDirEnum, ArchivedFileRead, Change, ChangeKind, DirEnum, FileRead, Request, PORT, WATCH_KEEPALIVE};
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use remote::Remote;
+use rkyv::rancor::Error;
+
+fn main() {
+    openssl::init();
+    let args= clap::Command::new("nas_rs")
+        .subcommand_negates_reqs(true)
+        .args_conflicts_with_subcommands(true)
+        .subcommand(
+            Command::new("sync")
+            .about("make a directory on one side a copy of one on the other")
+            .subcommand_required(true)
+            .subcommand(sync_command("push", "copy a local directory to the server"))
+            .subcommand(sync_command("pull", "copy a directory on the server to this machine"))
+            .subcommand(
+                Command::new("both")
+                .about("merge changes made on either side since the last run")
+                .arg(arg!(<local> "directory on this machine"))
+                .arg(arg!(<remote> "directory on the server, . for the top"))
+                .arg(
+                    arg!(--conflict <policy> "what to do with files changed on both sides")
+                    .value_parser(["keep-both", "newest", "prompt"])
+                    .default_value("keep-both")
+                )
+                .arg(arg!(--"dry-run" "only show what would change"))
+            )
+        ).arg(
+            arg!([file_path])
+            .required_unless_present_any(["trash", "restore-trash", "empty-trash", "rebuild-index"])
+            .value_parser(value_parser!(String))
+        ).arg(
+            arg!(--write)
+            .required(false)
+            .requires("in")
+        ).arg(
+            arg!(--delta "only send the parts of the file that differ from the copy on the server")
+            .required(false)
+            .requires("write")
+        ).arg(
+            arg!(--mkdir)
+            .required(false)
+        ).arg(
+            arg!(--delete)
+            .required(false)
+        ).arg(
+            arg!(--enumerate)
+            .required(false)
+        ).arg(
+            arg!(--versions "list the earlier versions of the file")
+            .required(false)
+        ).arg(
+            arg!(--thumbnail <size> "read a png of the image at most this many pixels wide and high")
+            .required(false)
+            .value_parser(value_parser!(u32))
+        ).arg(
+            arg!(--"read-version" <id> "read an earlier version of the file")
+            .required(false)
+            .value_parser(value_parser!(u64))
+        ).arg(
+            arg!(--"restore-version" <id> "put an earlier version of the file back in place")
+            .required(false)
+            .value_parser(value_parser!(u64))
+        ).arg(
+            arg!(--search <pattern> "find files below the directory whose name matches, * and ? work as wildcards")
+            .required(false)
+        ).arg(
+            arg!(--kind <kind> "with --search, only files or only directories")
+            .required(false)
+            .requires("search")
+            .value_parser(["any", "file", "dir"])
+            .default_value("any")
+        ).arg(
+            arg!(--"min-size" <bytes> "with --search, only files at least this big")
+            .required(false)
+            .requires("search")
+            .value_parser(value_parser!(u64))
+        ).arg(
+            arg!(--"max-size" <bytes> "with --search, only files at most this big")
+            .required(false)
+            .requires("search")
+            .value_parser(value_parser!(u64))
+        ).arg(
+            arg!(--"modified-within" <days> "with --search, only what changed in the last days")
+            .required(false)
+            .requires("search")
+            .value_parser(value_parser!(u64))
+        ).arg(
+            arg!(--"find-text" <words> "find text files below the directory containing all the words, if the server indexes them")
+            .required(false)
+        ).arg(
+            arg!(--rename <to> "move the file or directory to another path")
+            .required(false)
+        ).arg(
+            arg!(--copy <to> "copy the file or directory to another path")
+            .required(false)
+        ).arg(
+            arg!(--lock "lock the file so nobody else can change it, or renew the lock")
+            .required(false)
+        ).arg(
+            arg!(--shared "with --lock, let others lock it too, they still can't change it")
+            .required(false)
+            .requires("lock")
+        ).arg(
+            arg!(--lease <secs> "with --lock, how long the lock lasts unless renewed")
+            .required(false)
+            .requires("lock")
+            .value_parser(value_parser!(u64))
+            .default_value("300")
+        ).arg(
+            arg!(--unlock "give up your lock on the file")
+            .required(false)
+        ).arg(
+            arg!(--watch "print changes in the directory as they happen, until interrupted")
+            .required(false)
+        ).arg(
+            arg!(--recursive "download the whole directory to --out, with --watch also print changes in subdirectories")
+            .required(false)
+        ).arg(
+            arg!(--trash "list deleted files")
+            .required(false)
+        ).arg(
+            arg!(--"restore-trash" <id> "move a deleted file back where it was")
+            .required(false)
+            .value_parser(value_parser!(u64))
+        ).arg(
+            arg!(--"empty-trash" "permanently delete everything in the trash")
+            .required(false)
+        ).arg(
+            arg!(--"rebuild-index" "have the server index the contents of every file again")
+            .required(false)
+        ).arg(
+            arg!(--cert <file> "client certificate, the server keeps a trash per certificate")
+            .global(true)
+            .required(false)
+            .requires("key")
+        ).arg(
+            arg!(--key <file> "private key of the client certificate")
+            .global(true)
+            .required(false)
+            .requires("cert")
+        ).arg(
+            arg!(--ip <ip>)
+            .global(true)
+            .required(false)
+            .value_parser(value_parser!(Ipv4Addr))
+        ).arg(
+            arg!(--port <port>)
+            .global(true)
+            .required(false)
+            .value_parser(value_parser!(u16))
+        ).arg(
+            arg!(--compress <algorithm> "compress file bodies: none, zstd or lz4")
+            .global(true)
+            .required(false)
+            .value_parser(value_parser!(Compression))
+        ).arg(
+            arg!(--keyfile <file> "encrypt file contents with a key read from this file, with --passphrase-env it only holds the salt and is made if it's missing")
+            .global(true)
+            .required(false)
+        ).arg(
+            arg!(--"passphrase-env" <var> "encrypt file contents with a passphrase read from this environment variable")
+            .global(true)
+            .required(false)
+            .requires("keyfile")
+        ).arg(
+            arg!(--"encrypt-names" "also encrypt file and directory names")
+            .global(true)
+            .required(false)
+        ).arg(
+            arg!(--in <in_file>)
+        ).arg(
+            arg!(--out <out_file>)
+            .required_unless_present_any(["write", "mkdir", "delete", "enumerate", "search", "find-text", "rename", "copy", "lock", "unlock", "watch", "versions", "restore-version", "trash", "restore-trash", "empty-trash", "rebuild-index"])
+        ).get_matches();
+    
+    let compression = *args.get_one("compress").unwrap_or(&Compression::None);
+    let encrypt_names = args.get_flag("encrypt-names");
+    let key = args.get_one::<String>("keyfile").map(|keyfile| match args.get_one::<String>("passphrase-env") {
+        Some(var) => {
+            let passphrase = std::env::var(var).expect("passphrase variable isn't set");
+            Key::from_passphrase(&passphrase, Path::new(keyfile), encrypt_names).expect("can't derive key")
+        },
+        None => Key::from_keyfile(Path::new(keyfile), encrypt_names).expect("can't read keyfile"),
+    });
+    let ip = *args.get_one("ip").unwrap_or(&Ipv4Addr::LOCALHOST);
+    let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+    connector.set_verify(SslVerifyMode::PEER);
+    connector.set_ca_file("CA.cert").unwrap();
+    if let (Some(cert), Some(key)) = (args.get_one::<String>("cert"), args.get_one::<String>("key")) {
+        connector.set_certificate_file(cert, SslFiletype::PEM).expect("can't read certificate");
+        connector.set_private_key_file(key, SslFiletype::PEM).expect("can't read key");
+    }
+    let remote = Remote {
+        address: SocketAddrV4::new(ip, *args.get_one("port").unwrap_or(&PORT)),
+        connector: connector.build(),
+        compression,
+        key,
+    };
+    let key = &remote.key;
+
+    if let Some(("sync", args)) = args.subcommand() {
+        let (name, args) = args.subcommand().unwrap();
+        let local = Path::new(args.get_one::<String>("local").unwrap());
+        let remote_root = args.get_one::<String>("remote").unwrap();
+        let result = if name == "both" {
+            let conflict = match args.get_one::<String>("conflict").unwrap().as_str() {
+                "newest" => bisync::ConflictPolicy::Newest,
+                "prompt" => bisync::ConflictPolicy::Prompt,
+                _ => bisync::ConflictPolicy::KeepBoth,
+            };
+            bisync::run(&remote, local, remote_root, &bisync::Options { conflict, dry_run: args.get_flag("dry-run") })
+        } else {
+            let direction = if name == "push" { sync::Direction::Push } else { sync::Direction::Pull };
+            let options = sync::Options { direction, delete: args.get_flag("delete"), dry_run: args.get_flag("dry-run") };
+            sync::run(&remote, local, remote_root, &options)
+        };
+        if let Err(err) = result {
+            eprintln!("sync failed: {err}");
+            std::process::exit(1);
+        }
+        return;
+    }
+
+    if args.get_flag("delta") {
+        let data = std::fs::read(args.get_one::<String>("in").unwrap()).expect("can't read");
+        remote.update(args.get_one::<String>("file_path").unwrap(), &data).expect("couldn't send file");
+        println!("done writing");
+        return;
+    }
+
+    if args.get_flag("recursive") && !args.get_flag("watch") {
+        let root = args.get_one::<String>("file_path").unwrap();
+        let out = Path::new(args.get_one::<String>("out").unwrap());
+        match sync::download_dir(&remote, root, out) {
+            Ok(files) => println!("{files} file(s)"),
+            Err(err) => {
+                eprintln!("download failed: {err}");
+                std::process::exit(1);
+            },
+        }
+        return;
+    }
+
+    // the trash requests don't take a path
+    if let Some(pattern) = args.get_one::<String>("search") {
+        let kind = match args.get_one::<String>("kind").unwrap().as_str() {
+            "file" => SearchKind::File,
+            "dir" => SearchKind::Dir,
+            _ => SearchKind::Any,
+        };
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+        let query = Query {
+            pattern: pattern.clone(),
+            kind,
+            min_size: args.get_one("min-size").copied(),
+            max_size: args.get_one("max-size").copied(),
+            modified_after: args.get_one::<u64>("modified-within").map(|x| now.saturating_sub(Duration::from_secs(x * 24 * 3600)).as_nanos() as u64),
+        };
+        let root = args.get_one::<String>("file_path").unwrap();
+        remote.search(root, &query, |file| {
+            println!("{}\t{}", file.name, if file.is_dir { "dir".to_string() } else { format!("{} bytes", file.len) });
+        }).expect("search failed");
+        return;
+    }
+
+    if let Some(words) = args.get_one::<String>("find-text") {
+        let root = remote.remote_path(args.get_one::<String>("file_path").unwrap());
+        let mut stream = remote.connect().expect("Couldn't connect");
+        stream.write_struct::<Error>(&Request::ContentSearch { root, query: words.clone() }).expect("couldn't send request");
+        while stream.receive_u64::<Error>().expect("search failed") != 0 {
+            let hit = stream.receive_struct::<ContentHit, ArchivedContentHit, Error>().expect("couldn't receive result");
+            // the words found are marked **like this**
+            let mut snippet = String::new();
+            let mut last = 0;
+            for (start, end) in hit.highlights {
+                let (start, end) = (start as usize, end as usize);
+                snippet.push_str(&hit.snippet[last..start]);
+                snippet.push_str(&format!("**{}**", &hit.snippet[start..end]));
+                last = end;
+            }
+            snippet.push_str(&hit.snippet[last..]);
+            let path = match key {
+                Some(key) => hit.path.split('/').map(|x| key.decrypt_name(x)).collect::<Vec<_>>().join("/"),
+                None => hit.path,
+            };
+            println!("{path}: {}", snippet.split_whitespace().collect::<Vec<_>>().join(" "));
+        }
+        return;
+    }
+
+    let path = remote.remote_path(args.get_one::<String>("file_path").map(String::as_str).unwrap_or_default());
+
+    // file data
+    let file_data = args.get_one("write")
+        .filter(|x| **x)
+        .map(|_: &bool| {
+            let mut vec = vec![];
+            let mut file = args.get_one("in").map(|x: &String| Box::new(File::open(x).unwrap()) as Box<dyn Read>).unwrap_or_else(|| Box::new(std::io::stdin()));
+            file.read_to_end(&mut vec).expect("can't read");
+            match key {
+                Some(key) => key.encrypt(&vec).expect("can't encrypt"),
+                None => vec,
+            }
+        }).unwrap_or_default();
+    let (file_compression, file_data) = compress::encode(&file_data, compression).expect("can't compress");
+    // package data
+    let request = if args.get_flag("delete") {
+        Request::Delete { path }
+    } else if args.get_flag("write") {
+        Request::Write { path, len: file_data.len() as u64, compression: file_compression }
+    } else if args.get_flag("mkdir") {
+        Request::MkDir { path }
+    } else if args.get_flag("enumerate") {
+        Request::EnumDir { path }
+    } else if let Some(to) = args.get_one::<String>("rename") {
+        Request::Rename { path, to: remote.remote_path(to) }
+    } else if let Some(to) = args.get_one::<String>("copy") {
+        Request::Copy { path, to: remote.remote_path(to) }
+    } else if args.get_flag("lock") {
+        Request::Lock { path, exclusive: !args.get_flag("shared"), lease_secs: *args.get_one("lease").unwrap() }
+    } else if args.get_flag("unlock") {
+        Request::Unlock { path }
+    } else if args.get_flag("watch") {
+        Request::Watch { path, recursive: args.get_flag("recursive") }
+    } else if args.get_flag("versions") {
+        Request::ListVersions { path }
+    } else if let Some(size) = args.get_one::<u32>("thumbnail") {
+        Request::Thumbnail { path, size: *size }
+    } else if let Some(version) = args.get_one::<u64>("read-version") {
+        Request::ReadVersion { path, version: *version, accept: vec![compression] }
+    } else if let Some(version) = args.get_one::<u64>("restore-version") {
+        Request::RestoreVersion { path, version: *version }
+    } else if args.get_flag("trash") {
+        Request::ListTrash
+    } else if let Some(id) = args.get_one::<u64>("restore-trash") {
+        Request::RestoreTrash { id: *id }
+    } else if args.get_flag("empty-trash") {
+        Request::EmptyTrash
+    } else if args.get_flag("rebuild-index") {
+        Request::RebuildIndex
+    } else {
+        Request::Read { path, accept: vec![compression] }
+    };
+
+    // connect and send data
+    let mut stream = remote.connect().expect("Couldn't connect");
+    stream.write_struct::<Error>(&request).expect("couldn't send request");
+    if let Request::Write { .. } = request {
+        stream.write_buffer::<Error>(&file_data).expect("couldn't send file");
+        println!("done writing");
+    }
+
+    stream.inner.flush().unwrap();
+    if let Request::Read { .. } | Request::ReadVersion { .. } | Request::Thumbnail { .. } = request {
+        let file_info = stream.receive_struct::<FileRead, ArchivedFileRead, Error>().expect("couldn't recieve file");
+        let file = stream.receive_buffer::<Error>(file_info.len).expect("couldn't receive file");
+        let file = compress::decompress(&file, file_info.compression).expect("couldn't decompress file");
+        // thumbnails are made by the server, it can't have encrypted them
+        let file = match key {
+            Some(key) if !matches!(request, Request::Thumbnail { .. }) => key.decrypt(&file).expect("couldn't decrypt file"),
+            _ => file,
+        };
+        let mut out_file = args.get_one("out").map(|x: &String| Box::new(File::create(x).unwrap()) as Box<dyn Write>).unwrap_or_else(|| Box::new(std::io::stdout()));
+        out_file.write_all(&file).unwrap();
+        out_file.flush().unwrap();
+    } else if let Request::EnumDir { .. } = request {
+        let mut files = stream.receive_struct::<DirEnum, ArchivedDirEnum, Error>().expect("couldn't receive dir enum");
+        if let Some(key) = key {
+            files.files.iter_mut().for_each(|x| x.name = key.decrypt_name(&x.name));
+        }
+        println!("{files:?}");
+    } else if let Request::Lock { .. } = request {
+        let lock = stream.receive_struct::<LockInfo, ArchivedLockInfo, Error>().expect("couldn't lock");
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+        println!("{} lock held by {} for {}s", if lock.exclusive { "exclusive" } else { "shared" }, lock.owner, (lock.expires / 1_000_000_000).saturating_sub(now));
+    } else if let Request::Watch { .. } = request {
+        let decrypt = |path: String| match key {
+            Some(key) => path.split('/').map(|x| key.decrypt_name(x)).collect::<Vec<_>>().join("/"),
+            None => path,
+        };
+        loop {
+            match stream.receive_u64::<Error>().expect("lost the connection") {
+                0 => return,
+                WATCH_KEEPALIVE => continue,
+                _ => {},
+            }
+            let change = stream.receive_struct::<Change, ArchivedChange, Error>().expect("couldn't receive change");
+            let path = decrypt(change.path);
+            match change.kind {
+                ChangeKind::Create => println!("create {path}"),
+                ChangeKind::Modify => println!("modify {path}"),
+                ChangeKind::Delete => println!("delete {path}"),
+                ChangeKind::Rename => println!("rename {path} -> {}", decrypt(change.to.unwrap_or_default())),
+            }
+        }
+    } else if let Request::ListVersions { .. } = request {
+        let list = stream.receive_struct::<VersionList, ArchivedVersionList, Error>().expect("couldn't receive versions");
+        for version in list.versions {
+            println!("{}\t{} bytes", version.id, version.len);
+        }
+    } else if let Request::ListTrash = request {
+        let list = stream.receive_struct::<TrashList, ArchivedTrashList, Error>().expect("couldn't receive trash");
+        for item in list.items {
+            let path = match key {
+                Some(key) => item.path.split('/').map(|x| key.decrypt_name(x)).collect::<Vec<_>>().join("/"),
+                None => item.path,
+            };
+            println!("{}\t{}\t{}", item.id, if item.is_dir { "dir".to_string() } else { format!("{} bytes", item.len) }, path);
+        }
+    }
+
+    stream.receive_u64::<Error>().unwrap();
+}
+fn sync_command(name: &'static str, about: &'static str) -> Command {
+    Command::new(name)
+        .about(about)
+        .arg(arg!(<local> "directory on this machine"))
+        .arg(arg!(<remote> "directory on the server, . for the top"))
+        .arg(arg!(--delete "also delete what only the destination has"))
+        .arg(arg!(--"dry-run" "only show what would change"))
+}
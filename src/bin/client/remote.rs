@@ -0,0 +1,133 @@
+use std::{io::Write, net::{SocketAddrV4, TcpStream}};
+
+use nas_rs::{compress::{self, Compression}, crypto::Key, delta::{self, ArchivedSignature, Signature}, search::Query, ArchivedDirEnum, ArchivedFileInfo, ArchivedFileRead, DirEnum, FileInfo, FileRead, Request, StructStream};
+use openssl::ssl::{SslConnector, SslStream};
+use rkyv::rancor::{Error, Source};
+
+/// A server and how to talk to it. Paths are given in plaintext, they're
+/// encrypted here if the key says so.
+pub struct Remote {
+    pub address: SocketAddrV4,
+    pub connector: SslConnector,
+    pub compression: Compression,
+    pub key: Option<Key>,
+}
+impl Remote {
+    pub fn connect(&self) -> Result<StructStream<SslStream<TcpStream>>, Error> {
+        let tcp = TcpStream::connect(self.address).map_err(Error::new)?;
+        let stream = self.connector.connect(&self.address.ip().to_string(), tcp).map_err(Error::new)?;
+        Ok(StructStream::new(stream))
+    }
+    pub fn remote_path(&self, path: &str) -> String {
+        match &self.key {
+            Some(key) => key.encrypt_path(path).expect("can't encrypt path"),
+            None => path.to_string(),
+        }
+    }
+    /// Length of a local file of `len` bytes once it's stored on the server.
+    pub fn stored_len(&self, len: u64) -> u64 {
+        if self.key.is_some() { len + nas_rs::crypto::OVERHEAD } else { len }
+    }
+
+    /// Sends a request that's answered with nothing but the final status.
+    fn simple_request(&self, request: &Request) -> Result<(), Error> {
+        let mut stream = self.connect()?;
+        stream.write_struct::<Error>(request)?;
+        stream.receive_u64::<Error>()?;
+        Ok(())
+    }
+
+    /// Hands every match below `root` to `found` as it arrives, with the
+    /// path decrypted and the size of the plaintext.
+    pub fn search(&self, root: &str, query: &Query, mut found: impl FnMut(FileInfo)) -> Result<(), Error> {
+        // encrypted names can only be matched once they're decrypted here
+        let local = self.key.as_ref().is_some_and(|x| x.encrypts_names());
+        let mut stream = self.connect()?;
+        stream.write_struct::<Error>(&Request::Search {
+            root: self.remote_path(root),
+            pattern: if local { String::new() } else { query.pattern.clone() },
+            kind: query.kind,
+            min_size: query.min_size.map(|x| self.stored_len(x)),
+            max_size: query.max_size.map(|x| self.stored_len(x)),
+            modified_after: query.modified_after,
+        })?;
+        while stream.receive_u64::<Error>()? != 0 {
+            let mut file = stream.receive_struct::<FileInfo, ArchivedFileInfo, Error>()?;
+            if let Some(key) = &self.key {
+                file.name = file.name.split('/').map(|x| key.decrypt_name(x)).collect::<Vec<_>>().join("/");
+                if !file.is_dir {
+                    file.len = file.len.saturating_sub(nas_rs::crypto::OVERHEAD);
+                }
+            }
+            if !local || query.matches(&file) {
+                found(file);
+            }
+        }
+        Ok(())
+    }
+
+    /// Entries of the directory, with their names decrypted.
+    pub fn list(&self, path: &str) -> Result<Vec<FileInfo>, Error> {
+        let mut stream = self.connect()?;
+        stream.write_struct::<Error>(&Request::EnumDir { path: self.remote_path(path) })?;
+        let mut files = stream.receive_struct::<DirEnum, ArchivedDirEnum, Error>()?.files;
+        stream.receive_u64::<Error>()?;
+        if let Some(key) = &self.key {
+            files.iter_mut().for_each(|x| x.name = key.decrypt_name(&x.name));
+        }
+        Ok(files)
+    }
+    pub fn read(&self, path: &str) -> Result<Vec<u8>, Error> {
+        let mut stream = self.connect()?;
+        stream.write_struct::<Error>(&Request::Read { path: self.remote_path(path), accept: vec![self.compression] })?;
+        let file_info = stream.receive_struct::<FileRead, ArchivedFileRead, Error>()?;
+        let file = stream.receive_buffer::<Error>(file_info.len)?;
+        stream.receive_u64::<Error>()?;
+        let file = compress::decompress(&file, file_info.compression).map_err(Error::new)?;
+        match &self.key {
+            Some(key) => key.decrypt(&file).map_err(Error::new),
+            None => Ok(file),
+        }
+    }
+    pub fn write(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        let data = match &self.key {
+            Some(key) => key.encrypt(data).map_err(Error::new)?,
+            None => data.to_vec(),
+        };
+        let (compression, data) = compress::encode(&data, self.compression).map_err(Error::new)?;
+        let mut stream = self.connect()?;
+        stream.write_struct::<Error>(&Request::Write { path: self.remote_path(path), len: data.len() as u64, compression })?;
+        stream.write_buffer::<Error>(&data)?;
+        stream.inner.flush().map_err(Error::new)?;
+        stream.receive_u64::<Error>()?;
+        Ok(())
+    }
+    /// Replaces a file by sending only what differs from the copy on the
+    /// server. Encrypted files are sent whole, their ciphertext changes
+    /// completely every time they're encrypted.
+    pub fn update(&self, path: &str, data: &[u8]) -> Result<(), Error> {
+        if self.key.is_some() {
+            return self.write(path, data);
+        }
+        let mut stream = self.connect()?;
+        stream.write_struct::<Error>(&Request::Signature { path: path.to_string() })?;
+        let signature = stream.receive_struct::<Signature, ArchivedSignature, Error>()?;
+        stream.receive_u64::<Error>()?;
+
+        let delta = rkyv::to_bytes::<Error>(&delta::diff(&signature, data))?;
+        let (compression, body) = compress::encode(&delta, self.compression).map_err(Error::new)?;
+        let mut stream = self.connect()?;
+        stream.write_struct::<Error>(&Request::WriteDelta { path: path.to_string(), len: body.len() as u64, compression })?;
+        stream.write_buffer::<Error>(&body)?;
+        stream.inner.flush().map_err(Error::new)?;
+        stream.receive_u64::<Error>()?;
+        Ok(())
+    }
+    pub fn mkdir(&self, path: &str) -> Result<(), Error> {
+        self.simple_request(&Request::MkDir { path: self.remote_path(path) })
+    }
+    pub fn delete(&self, path: &str) -> Result<(), Error> {
+        self.simple_request(&Request::Delete { path: self.remote_path(path) })
+    }
+}
+
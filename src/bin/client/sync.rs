@@ -0,0 +1,277 @@
+//! `client sync push|pull`, making one side a copy of the other.
+//!
+//! Files are compared by size and modification time: a file is sent when the
+//! other side lacks it, holds a different size or an older copy.
+
+use std::{collections::BTreeMap, fs::File, io, path::Path, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use rkyv::rancor::{Error, Source};
+
+use crate::remote::Remote;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Push,
+    Pull,
+}
+
+pub struct Options {
+    pub direction: Direction,
+    /// also remove what only the destination has
+    pub delete: bool,
+    /// only report what would change
+    pub dry_run: bool,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct Entry {
+    pub is_dir: bool,
+    /// as it would be stored on the server
+    pub len: u64,
+    /// nanoseconds since the unix epoch
+    pub modified: u64,
+}
+
+/// Every file and directory below a root, keyed by `/` separated relative path.
+pub(crate) type Tree = BTreeMap<String, Entry>;
+
+/// name of the two-way sync state in the local root, never synced itself
+pub(crate) const STATE_FILE: &str = ".nas_rs-sync";
+
+#[derive(Debug)]
+enum Action {
+    Mkdir(String),
+    Copy(String),
+    Delete(String),
+}
+
+pub(crate) fn join(root: &str, path: &str) -> String {
+    if root == "." || root.is_empty() {
+        path.to_string()
+    } else {
+        format!("{}/{path}", root.trim_end_matches('/'))
+    }
+}
+
+pub(crate) fn nanos(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+/// `None` if the root doesn't exist.
+pub(crate) fn local_tree(root: &Path, remote: &Remote) -> io::Result<Option<Tree>> {
+    if !root.is_dir() {
+        return Ok(None);
+    }
+    let mut tree = Tree::new();
+    let mut stack = vec![String::new()];
+    while let Some(dir) = stack.pop() {
+        for entry in root.join(&dir).read_dir()? {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_symlink() || entry.file_name().to_string_lossy().starts_with(STATE_FILE) {
+                continue;
+            }
+            let Ok(name) = entry.file_name().into_string() else {
+                eprintln!("skipping non utf-8 name in {}", root.join(&dir).display());
+                continue;
+            };
+            let path = join(&dir, &name);
+            let metadata = entry.metadata()?;
+            let is_dir = file_type.is_dir();
+            if is_dir {
+                stack.push(path.clone());
+            }
+            tree.insert(path, Entry {
+                is_dir,
+                len: if is_dir { 0 } else { remote.stored_len(metadata.len()) },
+                modified: nanos(metadata.modified()?),
+            });
+        }
+    }
+    Ok(Some(tree))
+}
+
+/// Whether something is at `path` on the server. The server doesn't say why
+/// a request failed, so this asks the parent directories instead.
+fn remote_exists(path: &str, remote: &Remote) -> Result<bool, Error> {
+    let path = path.trim_matches('/');
+    if path.is_empty() || path == "." {
+        return Ok(true);
+    }
+    let (parent, name) = path.rsplit_once('/').unwrap_or((".", path));
+    match remote.list(parent) {
+        Ok(entries) => Ok(entries.iter().any(|x| x.name == name)),
+        Err(_) if !remote_exists(parent, remote)? => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// `None` if there's no directory at `root`, any other failure is an error.
+pub(crate) fn remote_tree(root: &str, remote: &Remote) -> Result<Option<Tree>, Error> {
+    let entries = match remote.list(root) {
+        Ok(entries) => entries,
+        Err(_) if !remote_exists(root, remote)? => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let mut tree = Tree::new();
+    let mut pending = vec![(String::new(), entries)];
+    while let Some((dir, entries)) = pending.pop() {
+        for entry in entries {
+            let path = join(&dir, &entry.name);
+            if entry.is_dir {
+                pending.push((path.clone(), remote.list(&join(root, &path))?));
+            }
+            tree.insert(path, Entry { is_dir: entry.is_dir, len: entry.len, modified: entry.modified });
+        }
+    }
+    Ok(Some(tree))
+}
+
+pub(crate) fn is_below(path: &str, dirs: &[String]) -> bool {
+    dirs.iter().any(|x| path.strip_prefix(x.as_str()).is_some_and(|x| x.starts_with('/')))
+}
+
+/// What turns `destination` into a copy of `source`, parents before children.
+fn plan(source: &Tree, destination: &Tree, delete: bool) -> Vec<Action> {
+    let mut actions = vec![];
+    // replaced or left alone along with everything below them
+    let mut skipped = vec![];
+    let mut removed = vec![];
+    for (path, entry) in source {
+        if is_below(path, &skipped) {
+            continue;
+        }
+        match destination.get(path) {
+            Some(existing) if existing.is_dir != entry.is_dir => {
+                if !delete {
+                    eprintln!("skipping {path}: a file on one side and a directory on the other, use --delete to replace it");
+                    skipped.push(path.clone());
+                    continue;
+                }
+                actions.push(Action::Delete(path.clone()));
+                removed.push(path.clone());
+            },
+            Some(existing) if entry.is_dir || (existing.len == entry.len && existing.modified >= entry.modified) => continue,
+            _ => {},
+        }
+        actions.push(if entry.is_dir { Action::Mkdir(path.clone()) } else { Action::Copy(path.clone()) });
+    }
+    if delete {
+        for path in destination.keys() {
+            // deleting a directory takes everything below it
+            if source.contains_key(path) || is_below(path, &removed) {
+                continue;
+            }
+            removed.push(path.clone());
+            actions.push(Action::Delete(path.clone()));
+        }
+    }
+    actions
+}
+
+/// Makes `remote_root` a copy of `local_root` or the other way around.
+pub fn run(remote: &Remote, local_root: &Path, remote_root: &str, options: &Options) -> Result<(), Error> {
+    let local = local_tree(local_root, remote).map_err(Error::new)?;
+    let server = remote_tree(remote_root, remote)?;
+    let (source, destination, create_root) = match options.direction {
+        Direction::Push => (local, server.clone(), server.is_none()),
+        Direction::Pull => (server, local.clone(), local.is_none()),
+    };
+    let Some(source) = source else {
+        return Err(Error::new(io::Error::new(io::ErrorKind::NotFound, "the source directory doesn't exist")));
+    };
+    let destination = destination.unwrap_or_default();
+    let actions = plan(&source, &destination, options.delete);
+
+    if options.dry_run {
+        if create_root {
+            println!("would create the destination directory");
+        }
+        for action in &actions {
+            match action {
+                Action::Mkdir(path) => println!("would create {path}/"),
+                Action::Copy(path) => println!("would copy {path} ({} bytes)", source[path].len),
+                Action::Delete(path) => println!("would delete {path}"),
+            }
+        }
+        println!("{} change(s)", actions.len());
+        return Ok(());
+    }
+
+    if create_root {
+        match options.direction {
+            Direction::Push => remote.mkdir(remote_root)?,
+            Direction::Pull => std::fs::create_dir_all(local_root).map_err(Error::new)?,
+        }
+    }
+    for action in &actions {
+        match (action, options.direction) {
+            (Action::Mkdir(path), Direction::Push) => remote.mkdir(&join(remote_root, path))?,
+            (Action::Mkdir(path), Direction::Pull) => std::fs::create_dir(local_root.join(path)).map_err(Error::new)?,
+            (Action::Copy(path), Direction::Push) => {
+                let data = std::fs::read(local_root.join(path)).map_err(Error::new)?;
+                if destination.contains_key(path) {
+                    remote.update(&join(remote_root, path), &data)?;
+                } else {
+                    remote.write(&join(remote_root, path), &data)?;
+                }
+            },
+            (Action::Copy(path), Direction::Pull) => {
+                download(remote, &join(remote_root, path), &local_root.join(path), source[path].modified)?;
+            },
+            (Action::Delete(path), Direction::Push) => remote.delete(&join(remote_root, path))?,
+            (Action::Delete(path), Direction::Pull) => remove_local(&local_root.join(path))?,
+        }
+        match action {
+            Action::Mkdir(path) => println!("created {path}/"),
+            Action::Copy(path) => println!("copied {path}"),
+            Action::Delete(path) => println!("deleted {path}"),
+        }
+    }
+    println!("{} change(s)", actions.len());
+    Ok(())
+}
+
+/// Saves the directory `remote_root` with everything in it as `local_root`,
+/// keeping the server's modification times. Returns the number of files.
+pub fn download_dir(remote: &Remote, remote_root: &str, local_root: &Path) -> Result<usize, Error> {
+    let Some(tree) = remote_tree(remote_root, remote)? else {
+        return Err(Error::new(io::Error::new(io::ErrorKind::NotFound, "the directory doesn't exist")));
+    };
+    std::fs::create_dir_all(local_root).map_err(Error::new)?;
+    let mut files = 0;
+    // directories sort before what's in them
+    for (path, entry) in &tree {
+        if entry.is_dir {
+            std::fs::create_dir_all(local_root.join(path)).map_err(Error::new)?;
+        } else {
+            download(remote, &join(remote_root, path), &local_root.join(path), entry.modified)?;
+            println!("copied {path}");
+            files += 1;
+        }
+    }
+    // filling a directory changes its time, so they come last and deepest first
+    for (path, entry) in tree.iter().rev().filter(|(_, x)| x.is_dir) {
+        let modified = UNIX_EPOCH + Duration::from_nanos(entry.modified);
+        // not every platform lets a directory be opened for this
+        let _ = File::open(local_root.join(path)).and_then(|x| x.set_modified(modified));
+    }
+    Ok(files)
+}
+
+/// Saves a file from the server, giving it the server's modification time
+/// so the next comparison sees it as unchanged.
+pub(crate) fn download(remote: &Remote, remote_path: &str, local_path: &Path, modified: u64) -> Result<(), Error> {
+    let data = remote.read(remote_path)?;
+    std::fs::write(local_path, data).map_err(Error::new)?;
+    let modified = UNIX_EPOCH + Duration::from_nanos(modified);
+    File::options().write(true).open(local_path).and_then(|x| x.set_modified(modified)).map_err(Error::new)
+}
+
+pub(crate) fn remove_local(path: &Path) -> Result<(), Error> {
+    if path.is_dir() {
+        std::fs::remove_dir_all(path).map_err(Error::new)
+    } else {
+        std::fs::remove_file(path).map_err(Error::new)
+    }
+}
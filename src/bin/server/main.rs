@@ -0,0 +1,651 @@
+mod metrics;
+mod watch;
+
+use std::{collections::HashSet, fs::{read, OpenOptions}, io::{ErrorKind, Read, Write}, net::{Ipv4Addr, SocketAddr, SocketAddrV4, TcpListener, TcpStream}, sync::{atomic::{AtomicBool, Ordering}, Arc, Mutex, MutexGuard}, thread, time::{Duration, Instant}};
+
+use clap::{arg, value_parser};
+use nas_rs::{compress::{self, Compression}, dedup::DedupBackend, delta::{self, Delta}, index::ContentIndex, locks::Locks, search::{self, Query}, storage::{self, LocalBackend, MemoryBackend, StorageBackend}, thumbnail, trash::{Trash, TrashList}, versions::{Retention, VersionList, Versions}, is_reserved, ArchivedRequest, ChangeKind, DirEnum, FileInfo, FileRead, Request, StructStream, META_DIR, PATH, PORT, WATCH_KEEPALIVE};
+use openssl::{nid::Nid, ssl::{Ssl, SslContext, SslContextBuilder, SslFiletype, SslMethod, SslStream, SslVerifyMode, SslVersion}, x509::X509};
+use rkyv::{rancor::{Error, Source}, util::AlignedVec};
+use tracing::{error, info, warn};
+use metrics::Metrics;
+use watch::Watch;
+use tracing_subscriber::{filter::filter_fn, layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
+
+fn main() {
+    openssl::init();
+    let args= clap::Command::new("nas_rs")
+        .arg(
+            arg!(--ip <ip>)
+            .required(false)
+            .value_parser(value_parser!(Ipv4Addr))
+        ).arg(
+            arg!(--port <port>)
+            .required(false)
+            .value_parser(value_parser!(u16))
+        ).arg(
+            arg!(--"drain-timeout" <seconds> "how long in-flight requests get to finish after SIGINT/SIGTERM")
+            .required(false)
+            .value_parser(value_parser!(u64))
+        ).arg(
+            arg!(--"audit-log" <file> "append-only log of mutating requests")
+            .required(false)
+        ).arg(
+            arg!(--"metrics-port" <port> "serve prometheus metrics on this local port")
+            .required(false)
+            .value_parser(value_parser!(u16))
+        ).arg(
+            arg!(--storage <backend> "where files are kept: local, dedup (deduplicated chunks) or memory")
+            .required(false)
+            .value_parser(["local", "dedup", "memory"])
+        ).arg(
+            arg!(--"gc-interval" <seconds> "how often unreferenced chunks are deleted with dedup storage")
+            .required(false)
+            .value_parser(value_parser!(u64))
+        ).arg(
+            arg!(--"keep-versions" <count> "earlier versions kept of each overwritten file, 0 turns history off")
+            .required(false)
+            .value_parser(value_parser!(usize))
+        ).arg(
+            arg!(--"version-max-age" <days> "drop earlier versions older than this")
+            .required(false)
+            .value_parser(value_parser!(u64))
+        ).arg(
+            arg!(--"trash-days" <days> "how long deleted files stay in the trash, 0 keeps them until it's emptied")
+            .required(false)
+            .value_parser(value_parser!(u64))
+        ).arg(
+            arg!(--"max-delta-growth" <MiB> "how much bigger than the old file and the data sent a delta may make a file, by repeating blocks")
+            .required(false)
+            .value_parser(value_parser!(u64))
+        ).arg(
+            arg!(--"content-index" "index the words in text files so they can be searched for")
+            .required(false)
+        ).arg(
+            arg!(--"rebuild-index" "build the content index from the files again before starting")
+            .required(false)
+            .requires("content-index")
+        ).get_matches();
+
+    let audit_log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(args.get_one::<String>("audit-log").map(String::as_str).unwrap_or("audit.log"))
+        .expect("couldn't open audit log");
+    tracing_subscriber::registry()
+        .with(tracing_subscriber::fmt::layer().with_filter(EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new("info"))))
+        .with(tracing_subscriber::fmt::layer().json().with_writer(Mutex::new(audit_log)).with_filter(filter_fn(|x| x.target() == "audit")))
+        .init();
+
+    let ip = *args.get_one("ip").unwrap_or(&Ipv4Addr::LOCALHOST);
+    let server_certificate = X509::from_pem(&read("SERVER.cert").unwrap()).unwrap();
+    server_certificate.subject_alt_names().expect("sign the certificate with an ip or domain").iter();
+    
+    let mut ssl_context = SslContextBuilder::new(SslMethod::tls_server()).unwrap();
+    ssl_context.set_min_proto_version(Some(SslVersion::TLS1_3)).unwrap();
+    ssl_context.set_ciphersuites("TLS_AES_128_GCM_SHA256:TLS_AES_256_GCM_SHA384:TLS_CHACHA20_POLY1305_SHA256").unwrap();
+    ssl_context.set_ca_file("CA.cert").unwrap();
+    ssl_context.set_certificate_file("SERVER.cert", SslFiletype::PEM).unwrap();
+    ssl_context.set_private_key_file("SERVER.key", SslFiletype::PEM).unwrap();
+    ssl_context.set_verify(SslVerifyMode::PEER);
+    let metrics = Arc::<Metrics>::default();
+    let storage: Arc<dyn StorageBackend> = match args.get_one::<String>("storage").map(String::as_str).unwrap_or("local") {
+        "dedup" => {
+            let backend = Arc::new(DedupBackend::new(PATH, CHUNKS_PATH).expect("couldn't open storage"));
+            let interval = Duration::from_secs(*args.get_one("gc-interval").unwrap_or(&3600));
+            spawn_garbage_collector(backend.clone(), metrics.clone(), interval);
+            backend
+        },
+        "memory" => Arc::new(MemoryBackend::new()),
+        _ => Arc::new(LocalBackend::new(PATH).expect("couldn't open storage")),
+    };
+    match storage.clean_up() {
+        Ok(0) => {},
+        Ok(removed) => info!("removed {removed} file(s) left by interrupted uploads"),
+        Err(err) => error!("couldn't clean up interrupted uploads: {err}"),
+    }
+    let versions = Versions::new(Retention {
+        max_count: *args.get_one("keep-versions").unwrap_or(&10),
+        max_age: args.get_one::<u64>("version-max-age").map(|x| Duration::from_secs(x * 24 * 3600)),
+    });
+    let trash = Trash::new(Some(*args.get_one::<u64>("trash-days").unwrap_or(&30))
+        .filter(|x| *x != 0)
+        .map(|x| Duration::from_secs(x * 24 * 3600)));
+    let index = args.get_flag("content-index").then(|| {
+        let loaded = match args.get_flag("rebuild-index") {
+            true => Ok(None),
+            false => ContentIndex::load(storage.as_ref()),
+        };
+        match loaded {
+            Ok(Some(index)) => return index,
+            Ok(None) => info!("building the content index"),
+            Err(err) => error!("the content index is broken, building it again: {err}"),
+        }
+        match ContentIndex::rebuild(storage.as_ref()) {
+            Ok(index) => index,
+            Err(err) => {
+                error!("couldn't build the content index, it only covers files written from now on: {err}");
+                ContentIndex::default()
+            },
+        }
+    });
+    // the watcher stops reporting once dropped, so it's kept until exit
+    let (watch, _watcher) = match storage.local_root().map(|x| x.canonicalize().and_then(|root| Watch::start(&root).map_err(std::io::Error::other))) {
+        Some(Ok((watch, watcher))) => (watch, Some(watcher)),
+        Some(Err(err)) => {
+            error!("couldn't watch the storage for changes, only reporting changes made through requests: {err}");
+            (Arc::new(Watch::default()), None)
+        },
+        None => (Arc::new(Watch::default()), None),
+    };
+    let max_delta_growth = *args.get_one::<u64>("max-delta-growth").unwrap_or(&64) << 20;
+    let ctx = Arc::new(Context { ssl: ssl_context.build(), drain: Drain::default(), metrics, storage, versions, trash, watch, locks: Locks::default(), index, max_delta_growth });
+    spawn_maintenance(ctx.clone());
+    spawn_index_saver(ctx.clone());
+    if let Some(port) = args.get_one::<u16>("metrics-port") {
+        metrics::serve(ctx.metrics.clone(), SocketAddr::from((Ipv4Addr::LOCALHOST, *port)));
+        spawn_usage_counter(ctx.clone());
+    }
+
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let shutdown_ref = shutdown.clone();
+    ctrlc::set_handler(move || shutdown_ref.store(true, Ordering::SeqCst)).expect("couldn't set signal handler");
+
+    let mut threads = vec![];
+    let listener = TcpListener::bind(SocketAddrV4::new(ip, *args.get_one("port").unwrap_or(&PORT))).expect("Couldn't bind port");
+    // polled so the signal handler can interrupt accepting
+    listener.set_nonblocking(true).unwrap();
+    while !shutdown.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((tcp, _)) => {
+                let ctx_ref = ctx.clone();
+                let thread = thread::spawn(move || handle_connection(tcp, ctx_ref));
+                threads.push(thread);
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::WouldBlock => thread::sleep(ACCEPT_POLL),
+            Err(err) => error!("couldn't accept connection: {err}"),
+        }
+        threads = reap(threads);
+    }
+    drop(listener);
+    ctx.drain.stopping.store(true, Ordering::SeqCst);
+
+    let deadline = Instant::now() + Duration::from_secs(*args.get_one("drain-timeout").unwrap_or(&30));
+    info!("shutting down, draining {} connection(s)", threads.len());
+    while !threads.is_empty() && Instant::now() < deadline {
+        thread::sleep(ACCEPT_POLL);
+        threads = reap(threads);
+    }
+    if !threads.is_empty() {
+        warn!("aborting {} connection(s)", threads.len());
+        let _in_flight = ctx.drain.abort(ctx.storage.as_ref());
+        ctx.save_index();
+        std::process::exit(1);
+    }
+    ctx.save_index();
+}
+
+const ACCEPT_POLL: Duration = Duration::from_millis(50);
+/// where the deduplicating backend keeps its chunks
+const CHUNKS_PATH: &str = "./chunks/";
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(3600);
+const INDEX_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+/// how often the storage is measured for the metrics
+const USAGE_INTERVAL: Duration = Duration::from_secs(300);
+/// how long a watch stays quiet before checking the client is still there
+const WATCH_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(30);
+
+struct Context {
+    ssl: SslContext,
+    drain: Drain,
+    metrics: Arc<Metrics>,
+    storage: Arc<dyn StorageBackend>,
+    versions: Versions,
+    trash: Trash,
+    watch: Arc<Watch>,
+    locks: Locks,
+    index: Option<ContentIndex>,
+    /// in bytes, see [`delta::apply`]
+    max_delta_growth: u64,
+}
+impl Context {
+    /// Brings the content index, if there is one, up to date with `path`.
+    fn reindex(&self, path: &str) {
+        if let Some(Err(err)) = self.index.as_ref().map(|x| x.update(self.storage.as_ref(), path)) {
+            warn!(path, "couldn't index: {err}");
+        }
+    }
+    fn save_index(&self) {
+        if let Some(Err(err)) = self.index.as_ref().map(|x| x.save(self.storage.as_ref())) {
+            error!("couldn't save the content index: {err}");
+        }
+    }
+}
+
+/// The `len` bytes of a request body. Reading fails if the client hangs up
+/// early or once the drain deadline has passed.
+struct Body<'a, R: Read> {
+    inner: R,
+    remaining: u64,
+    drain: &'a Drain,
+}
+impl<R: Read> Read for Body<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.drain.is_aborted() {
+            // not `Interrupted`, readers would just try again
+            return Err(std::io::Error::new(ErrorKind::ConnectionAborted, "server shutting down"));
+        }
+        if self.remaining == 0 {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.remaining.try_into().unwrap_or(usize::MAX));
+        let read = self.inner.read(&mut buf[..len])?;
+        if read == 0 {
+            return Err(ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        Ok(read)
+    }
+}
+
+/// Collects unreferenced chunks now and then every `interval`.
+fn spawn_garbage_collector(backend: Arc<DedupBackend>, metrics: Arc<Metrics>, interval: Duration) {
+    thread::spawn(move || loop {
+        match backend.collect_garbage() {
+            Ok(stats) => {
+                info!(
+                    logical_bytes = stats.logical_bytes,
+                    stored_bytes = stats.stored_bytes,
+                    saved_bytes = stats.logical_bytes.saturating_sub(stats.stored_bytes),
+                    chunks = stats.chunks,
+                    collected = stats.collected,
+                    skipped = stats.skipped,
+                    "deduplication"
+                );
+                metrics.set_dedup(stats);
+            },
+            Err(err) => error!("garbage collection failed: {err}"),
+        }
+        thread::sleep(interval);
+    });
+}
+
+/// Applies the version retention to every file and empties expired trash
+/// once an hour, so both also happen for files nobody touches anymore.
+fn spawn_maintenance(ctx: Arc<Context>) {
+    thread::spawn(move || loop {
+        if let Err(err) = ctx.versions.prune_all(ctx.storage.as_ref()) {
+            error!("pruning versions failed: {err}");
+        }
+        if let Err(err) = ctx.trash.expire(ctx.storage.as_ref()) {
+            error!("expiring trash failed: {err}");
+        }
+        thread::sleep(MAINTENANCE_INTERVAL);
+    });
+}
+
+/// Measures the storage for the metrics every [`USAGE_INTERVAL`], instead
+/// of on every scrape.
+fn spawn_usage_counter(ctx: Arc<Context>) {
+    thread::spawn(move || loop {
+        match ctx.storage.used_bytes() {
+            Ok(bytes) => ctx.metrics.set_storage_used(bytes),
+            Err(err) => error!("couldn't measure the storage: {err}"),
+        }
+        thread::sleep(USAGE_INTERVAL);
+    });
+}
+
+fn spawn_index_saver(ctx: Arc<Context>) {
+    if ctx.index.is_none() {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(INDEX_SAVE_INTERVAL);
+        ctx.save_index();
+    });
+}
+
+/// Tracks writes in progress so the storage can clean up after them if the
+/// server exits before they complete.
+#[derive(Default)]
+struct Drain {
+    /// set once no new connections are accepted, ends watches
+    stopping: AtomicBool,
+    aborted: AtomicBool,
+    in_flight: Mutex<HashSet<String>>,
+}
+impl Drain {
+    fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::SeqCst)
+    }
+    /// Stops every in-flight write and has the storage drop what they left.
+    /// Hold on to the returned guard until exit so no new write can register.
+    fn abort(&self, storage: &dyn StorageBackend) -> MutexGuard<'_, HashSet<String>> {
+        self.aborted.store(true, Ordering::SeqCst);
+        let in_flight = self.in_flight.lock().unwrap_or_else(|x| x.into_inner());
+        for path in in_flight.iter() {
+            storage.abandon_write(path);
+        }
+        in_flight
+    }
+    fn track<'a>(&'a self, path: &str) -> InFlight<'a> {
+        self.in_flight.lock().unwrap().insert(path.to_string());
+        InFlight { path: path.to_string(), drain: self }
+    }
+}
+
+struct InFlight<'a> {
+    path: String,
+    drain: &'a Drain,
+}
+impl Drop for InFlight<'_> {
+    fn drop(&mut self) {
+        self.drain.in_flight.lock().unwrap_or_else(|x| x.into_inner()).remove(&self.path);
+    }
+}
+
+fn reap(threads: Vec<thread::JoinHandle<()>>) -> Vec<thread::JoinHandle<()>> {
+    threads.into_iter().filter_map(|x| {
+        if !x.is_finished() {
+            return Some(x);
+        }
+        if let Some(err) = x.join().err() {
+            error!("connection thread panicked: {err:?}");
+        }
+        None
+    }).collect()
+}
+
+fn handle_connection(tcp: TcpStream, ctx: Arc<Context>) {
+    let _connection = ctx.metrics.connection();
+    let peer = tcp.peer_addr().map(|x| x.to_string()).unwrap_or_else(|_| "unknown".to_string());
+    tcp.set_nonblocking(false).unwrap();
+    tcp.set_read_timeout(Some(Duration::from_secs(500))).unwrap();
+    let mut ssl = match Ssl::new(&ctx.ssl).unwrap().accept(tcp) {
+        Ok(ssl) => ssl,
+        Err(err) => {
+            ctx.metrics.record_tls_failure();
+            warn!(%peer, "tls handshake failed: {err}");
+            return;
+        },
+    };
+    let client = client_identity(&ssl);
+    let mut stream = StructStream::new(&mut ssl);
+
+    // read struct
+    let request = match stream.receive_struct::<Request, ArchivedRequest, Error>() {
+        Ok(request) => request,
+        Err(err) => {
+            warn!(%peer, %client, "couldn't receive request: {err}");
+            return;
+        },
+    };
+
+    let start = Instant::now();
+    let result = handle_request(&mut stream, &request, &client, &ctx);
+    let duration = start.elapsed();
+    let duration_ms = duration.as_millis() as u64;
+    let (kind, path) = (request.kind(), request.path());
+    let (transfer, outcome) = match &result {
+        Ok(transfer) => (*transfer, "ok".to_string()),
+        Err(err) => (Transfer::default(), err.to_string()),
+    };
+    ctx.metrics.record_request(kind, result.is_ok(), duration, transfer.bytes_in, transfer.bytes_out);
+    if result.is_ok() {
+        info!(%peer, %client, kind, path, bytes_in = transfer.bytes_in, bytes_out = transfer.bytes_out, duration_ms, outcome);
+    } else {
+        warn!(%peer, %client, kind, path, bytes_in = transfer.bytes_in, bytes_out = transfer.bytes_out, duration_ms, outcome);
+    }
+    if request.is_mutating() {
+        info!(target: "audit", %peer, %client, kind, path, bytes_in = transfer.bytes_in, outcome);
+    }
+}
+
+/// Bytes of file data moved by a request.
+#[derive(Default, Clone, Copy)]
+struct Transfer {
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+/// The common name of the client certificate, if one was presented.
+fn client_identity<S>(ssl: &SslStream<S>) -> String {
+    ssl.ssl().peer_certificate()
+        .and_then(|cert| {
+            cert.subject_name().entries_by_nid(Nid::COMMONNAME).next()
+                .and_then(|x| x.data().as_utf8().ok().map(|s| s.to_string()))
+        })
+        .unwrap_or_else(|| "anonymous".to_string())
+}
+
+/// Fails if something is at `path` already.
+fn vacant(storage: &dyn StorageBackend, path: &str) -> Result<(), Error> {
+    match storage.stat(path) {
+        Ok(_) => Err(Error::new(std::io::Error::new(ErrorKind::AlreadyExists, "something is already there"))),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(Error::new(err)),
+    }
+}
+
+/// `client` is whose trash deleted files go to and who holds the locks it takes.
+fn handle_request<S: Read + Write>(stream: &mut StructStream<S>, request: &Request, client: &str, ctx: &Context) -> Result<Transfer, Error> {
+    let mut transfer = Transfer::default();
+    if is_reserved(request.path()) || matches!(request, Request::Rename { to, .. } | Request::Copy { to, .. } if is_reserved(to)) {
+        return Err(Error::new(std::io::Error::new(ErrorKind::PermissionDenied, "not allowed >:(")));
+    }
+    match request {
+        Request::Write { path, len, compression } => {
+            ctx.locks.check(client, path).map_err(Error::new)?;
+            let _in_flight = ctx.drain.track(path);
+            let existed = ctx.storage.stat(path).is_ok();
+            ctx.versions.preserve(ctx.storage.as_ref(), path).map_err(Error::new)?;
+            let body = Body { inner: &mut stream.inner, remaining: *len, drain: &ctx.drain };
+            let mut decoder = compress::decoder(body, *compression).map_err(Error::new)?;
+            ctx.storage.write(path, &mut decoder).map_err(Error::new)?;
+            ctx.watch.changed(if existed { ChangeKind::Modify } else { ChangeKind::Create }, path, None);
+            ctx.reindex(path);
+            transfer.bytes_in = *len;
+        },
+        Request::Signature { path } => {
+            let signature = match ctx.storage.read(path) {
+                Ok(data) => delta::signature(&data),
+                Err(err) if err.kind() == ErrorKind::NotFound => delta::signature(&[]),
+                Err(err) => return Err(Error::new(err)),
+            };
+            stream.write_struct::<Error>(&signature)?;
+        },
+        Request::WriteDelta { path, len, compression } => {
+            ctx.locks.check(client, path).map_err(Error::new)?;
+            let _in_flight = ctx.drain.track(path);
+            let mut body = vec![];
+            let reader = Body { inner: &mut stream.inner, remaining: *len, drain: &ctx.drain };
+            compress::decoder(reader, *compression).map_err(Error::new)?.read_to_end(&mut body).map_err(Error::new)?;
+            transfer.bytes_in = *len;
+            let mut aligned = AlignedVec::<16>::with_capacity(body.len());
+            aligned.extend_from_slice(&body);
+            let delta = rkyv::from_bytes::<Delta, Error>(&aligned)?;
+            let (existed, base) = match ctx.storage.read(path) {
+                Ok(data) => (true, data),
+                Err(err) if err.kind() == ErrorKind::NotFound => (false, vec![]),
+                Err(err) => return Err(Error::new(err)),
+            };
+            let data = delta::apply(&base, &delta, ctx.max_delta_growth).map_err(Error::new)?;
+            ctx.versions.preserve(ctx.storage.as_ref(), path).map_err(Error::new)?;
+            ctx.storage.write(path, &mut data.as_slice()).map_err(Error::new)?;
+            ctx.watch.changed(if existed { ChangeKind::Modify } else { ChangeKind::Create }, path, None);
+            ctx.reindex(path);
+        },
+        Request::MkDir { path } => {
+            ctx.storage.mkdir(path).map_err(Error::new)?;
+            ctx.watch.changed(ChangeKind::Create, path, None);
+        },
+        Request::Delete { path } => {
+            ctx.locks.check(client, path).map_err(Error::new)?;
+            ctx.trash.delete(ctx.storage.as_ref(), client, path).map_err(Error::new)?;
+            ctx.watch.changed(ChangeKind::Delete, path, None);
+            thumbnail::forget(ctx.storage.as_ref(), path);
+            if let Some(index) = &ctx.index {
+                index.remove(path);
+            }
+        },
+        Request::Read { path, accept } => {
+            let buf = ctx.storage.read(path).map_err(Error::new)?;
+            transfer.bytes_out = send_file(stream, &buf, accept)?;
+        },
+        Request::Thumbnail { path, size } => {
+            let png = thumbnail::thumbnail(ctx.storage.as_ref(), path, *size).map_err(Error::new)?;
+            // pngs are compressed already
+            transfer.bytes_out = send_file(stream, &png, &[])?;
+        },
+        Request::ListVersions { path } => {
+            let versions = ctx.versions.list(ctx.storage.as_ref(), path).map_err(Error::new)?;
+            stream.write_struct::<Error>(&VersionList { versions })?;
+        },
+        Request::ReadVersion { path, version, accept } => {
+            let buf = ctx.versions.read(ctx.storage.as_ref(), path, *version).map_err(Error::new)?;
+            transfer.bytes_out = send_file(stream, &buf, accept)?;
+        },
+        Request::RestoreVersion { path, version } => {
+            ctx.locks.check(client, path).map_err(Error::new)?;
+            ctx.versions.restore(ctx.storage.as_ref(), path, *version).map_err(Error::new)?;
+            ctx.watch.changed(ChangeKind::Modify, path, None);
+            ctx.reindex(path);
+        },
+        Request::Search { root, pattern, kind, min_size, max_size, modified_after } => {
+            let query = Query { pattern: pattern.clone(), kind: *kind, min_size: *min_size, max_size: *max_size, modified_after: *modified_after };
+            search::search(ctx.storage.as_ref(), root, &query, |mut file| {
+                file.locks = ctx.locks.holders(&file.name);
+                stream.write_u64::<Error>(1).and_then(|_| stream.write_struct::<Error>(&file)).map_err(std::io::Error::other)
+            }).map_err(Error::new)?;
+        },
+        Request::ContentSearch { root, query } => {
+            let index = ctx.index.as_ref().ok_or_else(|| Error::new(std::io::Error::new(ErrorKind::Unsupported, "the server keeps no content index")))?;
+            index.query(ctx.storage.as_ref(), root, query, |hit| {
+                stream.write_u64::<Error>(1).and_then(|_| stream.write_struct::<Error>(&hit)).map_err(std::io::Error::other)
+            }).map_err(Error::new)?;
+        },
+        Request::Rename { path, to } => {
+            ctx.locks.check(client, path).map_err(Error::new)?;
+            ctx.locks.check(client, to).map_err(Error::new)?;
+            vacant(ctx.storage.as_ref(), to)?;
+            ctx.storage.rename(path, to).map_err(Error::new)?;
+            ctx.locks.rename(path, to);
+            if let Err(err) = ctx.versions.rename(ctx.storage.as_ref(), path, to) {
+                warn!(path, to, "couldn't move the history: {err}");
+            }
+            thumbnail::forget(ctx.storage.as_ref(), path);
+            if let Some(index) = &ctx.index {
+                index.rename(path, to);
+            }
+            ctx.watch.changed(ChangeKind::Rename, path, Some(to));
+        },
+        Request::Copy { path, to } => {
+            ctx.locks.check(client, to).map_err(Error::new)?;
+            vacant(ctx.storage.as_ref(), to)?;
+            storage::copy(ctx.storage.as_ref(), path, to).map_err(Error::new)?;
+            ctx.watch.changed(ChangeKind::Create, to, None);
+            ctx.reindex(to);
+        },
+        Request::Lock { path, exclusive, lease_secs } => {
+            ctx.storage.stat(path).map_err(Error::new)?;
+            let lock = ctx.locks.lock(client, path, *exclusive, Duration::from_secs(*lease_secs)).map_err(Error::new)?;
+            stream.write_struct::<Error>(&lock)?;
+        },
+        Request::Unlock { path } => {
+            ctx.locks.unlock(client, path).map_err(Error::new)?;
+        },
+        Request::ListTrash => {
+            let items = ctx.trash.list(ctx.storage.as_ref(), client).map_err(Error::new)?;
+            stream.write_struct::<Error>(&TrashList { items })?;
+        },
+        Request::RestoreTrash { id } => {
+            let origin = ctx.trash.origin(ctx.storage.as_ref(), client, *id).map_err(Error::new)?;
+            ctx.locks.check(client, &origin).map_err(Error::new)?;
+            let path = ctx.trash.restore(ctx.storage.as_ref(), client, *id).map_err(Error::new)?;
+            ctx.watch.changed(ChangeKind::Create, &path, None);
+            ctx.reindex(&path);
+        },
+        Request::EmptyTrash => {
+            ctx.trash.empty(ctx.storage.as_ref(), client).map_err(Error::new)?;
+        },
+        Request::RebuildIndex => {
+            let index = ctx.index.as_ref().ok_or_else(|| Error::new(std::io::Error::new(ErrorKind::Unsupported, "the server keeps no content index")))?;
+            index.rebuild_in_place(ctx.storage.as_ref()).map_err(Error::new)?;
+            ctx.save_index();
+        },
+        Request::Watch { path, recursive } => {
+            if !ctx.storage.stat(path).map_err(Error::new)?.is_dir {
+                return Err(Error::new(std::io::Error::new(ErrorKind::NotADirectory, "only directories can be watched")));
+            }
+            let changes = ctx.watch.subscribe(path, *recursive);
+            let mut quiet_since = Instant::now();
+            while !ctx.drain.stopping.load(Ordering::SeqCst) {
+                match changes.recv_timeout(ACCEPT_POLL * 10) {
+                    Ok(change) => {
+                        stream.write_u64::<Error>(1)?;
+                        stream.write_struct::<Error>(&change)?;
+                        quiet_since = Instant::now();
+                    },
+                    // a write fails once the client is gone, which ends the watch
+                    Err(_) if quiet_since.elapsed() >= WATCH_KEEPALIVE_INTERVAL => {
+                        stream.write_u64::<Error>(WATCH_KEEPALIVE)?;
+                        quiet_since = Instant::now();
+                    },
+                    Err(_) => {},
+                }
+            }
+        },
+        Request::EnumDir { path } => {
+            let contents = ctx.storage.list(path).map_err(Error::new)?
+                .into_iter()
+                .filter(|(name, _)| name != META_DIR)
+                .map(|(name, metadata)| {
+                    let full_path = if path == "." { name.clone() } else { format!("{path}/{name}") };
+                    // one that can't be listed just shows as empty
+                    let entries = match metadata.is_dir {
+                        true => ctx.storage.list(&full_path).map(|x| x.len() as u64).unwrap_or(0),
+                        false => 0,
+                    };
+                    FileInfo {
+                        entries,
+                        locks: ctx.locks.holders(&full_path),
+                        ..search::file_info(name, &metadata)
+                    }
+                })
+                .collect();
+            stream.write_struct::<Error>(&DirEnum {
+                files: contents,
+            })?;
+        },
+    }
+    stream.write_u64::<Error>(0)?;
+    Ok(transfer)
+}
+
+/// Sends a [`FileRead`] and the file, compressed with the first of `accept`
+/// that's worth it. Returns the bytes sent.
+fn send_file<S: Read + Write>(stream: &mut StructStream<S>, buf: &[u8], accept: &[Compression]) -> Result<u64, Error> {
+    let preferred = accept.iter().copied().find(|x| *x != Compression::None).unwrap_or_default();
+    let (compression, body) = compress::encode(buf, preferred).map_err(Error::new)?;
+    stream.write_struct::<Error>(&FileRead { len: body.len() as u64, compression })?;
+    stream.write_buffer::<Error>(&body)?;
+    Ok(body.len() as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn aborted_drain_ends_body_reads() {
+        let drain = Drain::default();
+        let mut body = Body { inner: std::io::repeat(1), remaining: u64::MAX, drain: &drain };
+        let mut buf = [0; 16];
+        body.read_exact(&mut buf).unwrap();
+        let _guard = drain.abort(&MemoryBackend::new());
+        let err = body.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionAborted);
+        assert_eq!(std::io::copy(&mut body, &mut std::io::sink()).unwrap_err().kind(), ErrorKind::ConnectionAborted);
+    }
+}
@@ -0,0 +1,138 @@
+use std::{collections::BTreeMap, fmt::Write as _, io::{BufRead, BufReader, Write}, net::{SocketAddr, TcpListener, TcpStream}, sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex}, thread, time::Duration};
+
+use nas_rs::dedup::DedupStats;
+use tracing::{error, warn};
+
+/// upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 10] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 30.0];
+
+#[derive(Default)]
+struct Histogram {
+    counts: [u64; BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+impl Histogram {
+    fn observe(&mut self, value: f64) {
+        for (count, bound) in self.counts.iter_mut().zip(BUCKETS) {
+            if value <= bound {
+                *count += 1;
+            }
+        }
+        self.count += 1;
+        self.sum += value;
+    }
+}
+
+/// Counters exported in the prometheus text format.
+#[derive(Default)]
+pub struct Metrics {
+    requests: Mutex<BTreeMap<(&'static str, &'static str), u64>>,
+    latency: Mutex<BTreeMap<&'static str, Histogram>>,
+    bytes_in: AtomicU64,
+    bytes_out: AtomicU64,
+    active_connections: AtomicU64,
+    tls_failures: AtomicU64,
+    /// counted now and then in the background, walking the storage takes a while
+    storage_used: AtomicU64,
+    /// from the last garbage collection, if deduplication is on
+    dedup: Mutex<Option<DedupStats>>,
+}
+impl Metrics {
+    pub fn record_request(&self, kind: &'static str, ok: bool, duration: Duration, bytes_in: u64, bytes_out: u64) {
+        let outcome = if ok { "ok" } else { "error" };
+        *self.requests.lock().unwrap().entry((kind, outcome)).or_default() += 1;
+        self.latency.lock().unwrap().entry(kind).or_default().observe(duration.as_secs_f64());
+        self.bytes_in.fetch_add(bytes_in, Ordering::Relaxed);
+        self.bytes_out.fetch_add(bytes_out, Ordering::Relaxed);
+    }
+    pub fn record_tls_failure(&self) {
+        self.tls_failures.fetch_add(1, Ordering::Relaxed);
+    }
+    pub fn set_storage_used(&self, bytes: u64) {
+        self.storage_used.store(bytes, Ordering::Relaxed);
+    }
+    pub fn set_dedup(&self, stats: DedupStats) {
+        *self.dedup.lock().unwrap() = Some(stats);
+    }
+    /// Counts the connection as active until the guard is dropped.
+    pub fn connection(&self) -> ConnectionGuard<'_> {
+        self.active_connections.fetch_add(1, Ordering::Relaxed);
+        ConnectionGuard(self)
+    }
+
+    fn render(&self) -> String {
+        let mut out = String::new();
+        out.push_str("# HELP nas_requests_total Requests handled, by kind and outcome.\n# TYPE nas_requests_total counter\n");
+        for ((kind, outcome), count) in self.requests.lock().unwrap().iter() {
+            writeln!(out, "nas_requests_total{{kind=\"{kind}\",outcome=\"{outcome}\"}} {count}").unwrap();
+        }
+        out.push_str("# HELP nas_request_duration_seconds Time spent handling a request.\n# TYPE nas_request_duration_seconds histogram\n");
+        for (kind, histogram) in self.latency.lock().unwrap().iter() {
+            for (count, bound) in histogram.counts.iter().zip(BUCKETS) {
+                writeln!(out, "nas_request_duration_seconds_bucket{{kind=\"{kind}\",le=\"{bound}\"}} {count}").unwrap();
+            }
+            writeln!(out, "nas_request_duration_seconds_bucket{{kind=\"{kind}\",le=\"+Inf\"}} {}", histogram.count).unwrap();
+            writeln!(out, "nas_request_duration_seconds_sum{{kind=\"{kind}\"}} {}", histogram.sum).unwrap();
+            writeln!(out, "nas_request_duration_seconds_count{{kind=\"{kind}\"}} {}", histogram.count).unwrap();
+        }
+        let scalars = [
+            ("nas_received_bytes_total", "counter", "File data received from clients.", self.bytes_in.load(Ordering::Relaxed)),
+            ("nas_sent_bytes_total", "counter", "File data sent to clients.", self.bytes_out.load(Ordering::Relaxed)),
+            ("nas_active_connections", "gauge", "Connections currently open.", self.active_connections.load(Ordering::Relaxed)),
+            ("nas_tls_handshake_failures_total", "counter", "Connections dropped during the tls handshake.", self.tls_failures.load(Ordering::Relaxed)),
+            ("nas_storage_used_bytes", "gauge", "Space taken by the stored data.", self.storage_used.load(Ordering::Relaxed)),
+        ];
+        let dedup = *self.dedup.lock().unwrap();
+        let dedup = dedup.iter().flat_map(|x| [
+            ("nas_dedup_logical_bytes", "gauge", "Size of all files before deduplication.", x.logical_bytes),
+            ("nas_dedup_stored_bytes", "gauge", "Size of the stored chunks.", x.stored_bytes),
+            ("nas_dedup_chunks", "gauge", "Number of stored chunks.", x.chunks),
+        ]);
+        for (name, kind, help, value) in scalars.into_iter().chain(dedup) {
+            writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}\n{name} {value}").unwrap();
+        }
+        out
+    }
+}
+
+pub struct ConnectionGuard<'a>(&'a Metrics);
+impl Drop for ConnectionGuard<'_> {
+    fn drop(&mut self) {
+        self.0.active_connections.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Serves `GET /metrics` over plain http on a background thread.
+pub fn serve(metrics: Arc<Metrics>, address: SocketAddr) {
+    let listener = TcpListener::bind(address).expect("Couldn't bind metrics port");
+    thread::spawn(move || {
+        for tcp in listener.incoming() {
+            let result = tcp.and_then(|tcp| respond(tcp, &metrics));
+            if let Err(err) = result {
+                warn!("metrics request failed: {err}");
+            }
+        }
+        error!("metrics listener stopped");
+    });
+}
+
+fn respond(mut tcp: TcpStream, metrics: &Metrics) -> std::io::Result<()> {
+    tcp.set_read_timeout(Some(Duration::from_secs(5)))?;
+    let mut reader = BufReader::new(&tcp);
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // skip the headers
+    let mut line = String::new();
+    while reader.read_line(&mut line)? > 2 {
+        line.clear();
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => ("200 OK", metrics.render()),
+        _ => ("404 Not Found", String::new()),
+    };
+    write!(tcp, "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}", body.len())?;
+    tcp.flush()
+}
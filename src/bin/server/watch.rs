@@ -0,0 +1,276 @@
+//! Hands out the changes below the storage root to [`Request::Watch`]ers.
+//!
+//! Storage kept in a local directory is watched with the OS (inotify on
+//! linux), so changes made behind the server's back show up too. Other
+//! storage only reports the changes made through requests.
+//!
+//! [`Request::Watch`]: nas_rs::Request::Watch
+
+use std::{collections::HashSet, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, mpsc::{channel, Receiver, RecvTimeoutError, Sender}, Arc, Mutex}, time::{Duration, Instant}};
+
+use nas_rs::{is_reserved, storage::{is_temp_file, temp_file_target}, Change, ChangeKind};
+use notify::{event::{ModifyKind, RenameMode}, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher as _};
+use tracing::warn;
+
+/// how long the first half of a rename waits for the second before it's
+/// taken as a move out of the storage
+const MOVE_OUT_WAIT: Duration = Duration::from_millis(500);
+
+struct Subscriber {
+    id: u64,
+    path: String,
+    recursive: bool,
+    sender: Sender<Change>,
+}
+impl Subscriber {
+    fn wants(&self, path: &str) -> bool {
+        let parent = path.rsplit_once('/').map(|(x, _)| x).unwrap_or("");
+        let dir = self.path.trim_matches('/');
+        if dir.is_empty() || dir == "." {
+            return self.recursive || parent.is_empty();
+        }
+        parent == dir || (self.recursive && parent.strip_prefix(dir).is_some_and(|x| x.starts_with('/')))
+    }
+}
+
+#[derive(Default)]
+pub struct Watch {
+    subscribers: Mutex<Vec<Subscriber>>,
+    next_id: AtomicU64,
+    /// set when the OS reports the changes, requests then don't need to
+    from_os: bool,
+}
+impl Watch {
+    /// Starts watching `root` for changes. Keep the returned watcher around
+    /// for as long as changes should be reported.
+    pub fn start(root: &Path) -> notify::Result<(Arc<Self>, RecommendedWatcher)> {
+        let watch = Arc::new(Self { from_os: true, ..Self::default() });
+        let events = Arc::new(Mutex::new(Events::new(root.to_path_buf())));
+        let (watch_ref, events_ref) = (watch.clone(), events.clone());
+        let mut watcher = notify::recommended_watcher(move |event: notify::Result<Event>| match event {
+            Ok(event) => {
+                let changes = events_ref.lock().unwrap().changes(event);
+                for change in changes {
+                    watch_ref.publish(change);
+                }
+            },
+            Err(err) => warn!("watching files failed: {err}"),
+        })?;
+        watcher.watch(root, RecursiveMode::Recursive)?;
+        // stops with the watcher, which holds the other reference to the events
+        let (watch_ref, events) = (watch.clone(), Arc::downgrade(&events));
+        std::thread::spawn(move || loop {
+            std::thread::sleep(MOVE_OUT_WAIT);
+            let Some(events) = events.upgrade() else {
+                break;
+            };
+            let change = events.lock().unwrap().moved_out(MOVE_OUT_WAIT);
+            if let Some(change) = change {
+                watch_ref.publish(change);
+            }
+        });
+        Ok((watch, watcher))
+    }
+
+    pub fn subscribe(&self, path: &str, recursive: bool) -> Subscription<'_> {
+        let (sender, receiver) = channel();
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.subscribers.lock().unwrap().push(Subscriber { id, path: path.to_string(), recursive, sender });
+        Subscription { watch: self, id, receiver }
+    }
+
+    /// Reports a change made by a request, unless the OS already does.
+    pub fn changed(&self, kind: ChangeKind, path: &str, to: Option<&str>) {
+        if !self.from_os {
+            self.publish(Change { kind, path: path.trim_matches('/').to_string(), to: to.map(|x| x.trim_matches('/').to_string()) });
+        }
+    }
+
+    fn publish(&self, change: Change) {
+        // moves into and out of the server's own data, like the trash, are deletes and creates
+        let change = match (is_reserved(&change.path), change.to.as_deref().map(is_reserved)) {
+            (true, None | Some(true)) => return,
+            (false, Some(true)) => Change { kind: ChangeKind::Delete, path: change.path, to: None },
+            (true, Some(false)) => Change { kind: ChangeKind::Create, path: change.to.unwrap(), to: None },
+            (false, _) => change,
+        };
+        // subscribers take themselves off once their connection is gone
+        for x in self.subscribers.lock().unwrap().iter() {
+            if x.wants(&change.path) || change.to.as_deref().is_some_and(|to| x.wants(to)) {
+                let _ = x.sender.send(change.clone());
+            }
+        }
+    }
+}
+
+/// The changes one watcher gets, until it's dropped.
+pub struct Subscription<'a> {
+    watch: &'a Watch,
+    id: u64,
+    receiver: Receiver<Change>,
+}
+impl Subscription<'_> {
+    pub fn recv_timeout(&self, timeout: Duration) -> Result<Change, RecvTimeoutError> {
+        self.receiver.recv_timeout(timeout)
+    }
+}
+impl Drop for Subscription<'_> {
+    fn drop(&mut self) {
+        self.watch.subscribers.lock().unwrap().retain(|x| x.id != self.id);
+    }
+}
+
+fn relative(root: &Path, path: &Path) -> Option<String> {
+    let parts: Vec<_> = path.strip_prefix(root).ok()?.iter().map(|x| x.to_str()).collect::<Option<_>>()?;
+    if parts.is_empty() {
+        return None;
+    }
+    Some(parts.join("/"))
+}
+
+fn is_temp(path: &Path) -> bool {
+    path.file_name().is_some_and(|x| is_temp_file(&x.to_string_lossy()))
+}
+
+/// Turns the OS's events into changes. A rename comes as a `From` half, a
+/// `To` half and then both together, a half on its own moved something out
+/// of or into the storage.
+struct Events {
+    root: PathBuf,
+    /// the `From` half of a rename and when it came, until it's known whether
+    /// a `To` follows
+    moved_from: Option<(PathBuf, Option<usize>, Instant)>,
+    /// temp files of the uploads to files that didn't exist yet
+    new_uploads: HashSet<PathBuf>,
+}
+impl Events {
+    fn new(root: PathBuf) -> Self {
+        Self { root, moved_from: None, new_uploads: HashSet::new() }
+    }
+
+    fn changes(&mut self, event: Event) -> Vec<Change> {
+        let mut changes = vec![];
+        if let Some((path, tracker, _)) = self.moved_from.take() {
+            if matches!(event.kind, EventKind::Modify(ModifyKind::Name(RenameMode::To))) && event.tracker() == tracker {
+                // reported with the `Both` that follows
+                return changes;
+            }
+            changes.extend(self.deleted(path));
+        }
+        changes.extend(self.change(event));
+        changes
+    }
+
+    /// Reports the `From` half of a rename as a delete once it's waited
+    /// `wait` for its `To`.
+    fn moved_out(&mut self, wait: Duration) -> Option<Change> {
+        match self.moved_from.take()? {
+            (path, _, at) if at.elapsed() >= wait => self.deleted(path),
+            pending => {
+                self.moved_from = Some(pending);
+                None
+            },
+        }
+    }
+
+    fn deleted(&mut self, path: PathBuf) -> Option<Change> {
+        self.new_uploads.remove(&path);
+        if is_temp(&path) {
+            return None;
+        }
+        Some(Change { kind: ChangeKind::Delete, path: relative(&self.root, &path)?, to: None })
+    }
+
+    fn change(&mut self, event: Event) -> Option<Change> {
+        let path = event.paths.first()?.clone();
+        let kind = match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+                let to = event.paths.get(1)?;
+                // finished uploads are renamed into place
+                if is_temp(&path) {
+                    let kind = if self.new_uploads.remove(&path) { ChangeKind::Create } else { ChangeKind::Modify };
+                    return Some(Change { kind, path: relative(&self.root, to)?, to: None });
+                }
+                return Some(Change { kind: ChangeKind::Rename, path: relative(&self.root, &path)?, to: Some(relative(&self.root, to)?) });
+            },
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                self.moved_from = Some((path, event.tracker(), Instant::now()));
+                return None;
+            },
+            // the `To` halves of renames inside the storage don't get here
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => ChangeKind::Create,
+            EventKind::Modify(ModifyKind::Name(_)) => ChangeKind::Rename,
+            EventKind::Create(_) => {
+                let target = path.file_name().and_then(|x| temp_file_target(&x.to_string_lossy()).map(|x| path.with_file_name(x)));
+                if target.is_some_and(|x| x.symlink_metadata().is_err()) {
+                    self.new_uploads.insert(path.clone());
+                }
+                ChangeKind::Create
+            },
+            EventKind::Modify(ModifyKind::Metadata(_)) => return None,
+            EventKind::Modify(_) => ChangeKind::Modify,
+            EventKind::Remove(_) => return self.deleted(path),
+            _ => return None,
+        };
+        if is_temp(&path) {
+            return None;
+        }
+        Some(Change { kind, path: relative(&self.root, &path)?, to: None })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::CreateKind;
+
+    fn event(kind: EventKind, paths: &[&Path], tracker: Option<usize>) -> Event {
+        let event = paths.iter().fold(Event::new(kind), |event, x| event.add_path(x.to_path_buf()));
+        match tracker {
+            Some(tracker) => event.set_tracker(tracker),
+            None => event,
+        }
+    }
+
+    fn kinds(changes: &[Change]) -> Vec<(ChangeKind, &str)> {
+        changes.iter().map(|x| (x.kind, x.path.as_str())).collect()
+    }
+
+    #[test]
+    fn finished_uploads_create_new_files() {
+        let root = std::env::temp_dir().join(format!("nas_rs-watch-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        let mut events = Events::new(root.clone());
+        let (temp, target) = (root.join(".a.0.nas_rs-tmp"), root.join("a"));
+        let both = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+
+        assert!(events.changes(event(EventKind::Create(CreateKind::File), &[&temp], None)).is_empty());
+        assert_eq!(kinds(&events.changes(event(both, &[&temp, &target], None))), [(ChangeKind::Create, "a")]);
+        std::fs::write(&target, b"").unwrap();
+        assert!(events.changes(event(EventKind::Create(CreateKind::File), &[&temp], None)).is_empty());
+        assert_eq!(kinds(&events.changes(event(both, &[&temp, &target], None))), [(ChangeKind::Modify, "a")]);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn rename_halves() {
+        let root = Path::new("/storage");
+        let mut events = Events::new(root.to_path_buf());
+        let name = |mode| EventKind::Modify(ModifyKind::Name(mode));
+        let (a, b) = (root.join("a"), root.join("b"));
+
+        // inside the storage, only the last of the three events counts
+        assert!(events.changes(event(name(RenameMode::From), &[&a], Some(1))).is_empty());
+        assert!(events.changes(event(name(RenameMode::To), &[&b], Some(1))).is_empty());
+        let changes = events.changes(event(name(RenameMode::Both), &[&a, &b], Some(1)));
+        assert_eq!((kinds(&changes), changes[0].to.as_deref()), (vec![(ChangeKind::Rename, "a")], Some("b")));
+
+        // moved in and out
+        assert_eq!(kinds(&events.changes(event(name(RenameMode::To), &[&a], Some(2)))), [(ChangeKind::Create, "a")]);
+        assert!(events.changes(event(name(RenameMode::From), &[&a], Some(3))).is_empty());
+        assert_eq!(kinds(&events.changes(event(EventKind::Create(CreateKind::File), &[&b], None))), [(ChangeKind::Delete, "a"), (ChangeKind::Create, "b")]);
+        assert!(events.changes(event(name(RenameMode::From), &[&b], Some(4))).is_empty());
+        assert!(events.moved_out(Duration::from_secs(60)).is_none());
+        assert_eq!(events.moved_out(Duration::ZERO).map(|x| (x.kind, x.path)), Some((ChangeKind::Delete, "b".to_string())));
+    }
+}
@@ -0,0 +1,104 @@
+//! Compression of file bodies on the wire: the client says what it accepts
+//! and the sender uses one of those, except for data that's compressed
+//! already or that a sample shows won't get much smaller.
+
+use std::{fmt::Display, io::{self, Read, Write}, str::FromStr};
+
+use rkyv::{Archive, Deserialize, Serialize};
+
+/// Encoding of a file body on the wire.
+#[derive(Serialize, Deserialize, Archive, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Compression {
+    #[default]
+    None,
+    Zstd,
+    Lz4,
+}
+impl Compression {
+    pub const ALL: [Compression; 3] = [Compression::None, Compression::Zstd, Compression::Lz4];
+}
+impl Display for Compression {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Zstd => "zstd",
+            Compression::Lz4 => "lz4",
+        })
+    }
+}
+impl FromStr for Compression {
+    type Err = String;
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Compression::ALL.into_iter()
+            .find(|x| x.to_string() == s)
+            .ok_or_else(|| format!("unknown compression {s:?}"))
+    }
+}
+
+/// how much of the data is test-compressed when deciding whether to compress
+const SAMPLE_LEN: usize = 64 * 1024;
+/// leading bytes of formats that are already compressed
+const COMPRESSED_MAGIC: &[&[u8]] = &[
+    b"\x1f\x8b", // gzip
+    b"PK\x03\x04", // zip, docx, jar...
+    b"\x28\xb5\x2f\xfd", // zstd
+    b"\x04\x22\x4d\x18", // lz4
+    b"\xfd7zXZ\x00", // xz
+    b"7z\xbc\xaf\x27\x1c",
+    b"BZh",
+    b"Rar!",
+    b"\x89PNG",
+    b"\xff\xd8\xff", // jpeg
+    b"GIF8",
+    b"OggS",
+    b"fLaC",
+    b"ID3", // mp3
+];
+
+/// Picks `preferred` unless the data looks like it won't shrink.
+pub fn choose(data: &[u8], preferred: Compression) -> Compression {
+    if preferred == Compression::None || data.is_empty() {
+        return Compression::None;
+    }
+    if COMPRESSED_MAGIC.iter().any(|x| data.starts_with(x)) {
+        return Compression::None;
+    }
+    let sample = &data[..data.len().min(SAMPLE_LEN)];
+    match compress(sample, preferred) {
+        Ok(compressed) if compressed.len() * 10 <= sample.len() * 9 => preferred,
+        _ => Compression::None,
+    }
+}
+
+pub fn compress(data: &[u8], compression: Compression) -> io::Result<Vec<u8>> {
+    match compression {
+        Compression::None => Ok(data.to_vec()),
+        Compression::Zstd => zstd::encode_all(data, 3),
+        Compression::Lz4 => {
+            let mut encoder = lz4_flex::frame::FrameEncoder::new(vec![]);
+            encoder.write_all(data)?;
+            encoder.finish().map_err(io::Error::other)
+        },
+    }
+}
+
+/// Compresses with `preferred` if it's worth it, returning what was used.
+pub fn encode(data: &[u8], preferred: Compression) -> io::Result<(Compression, Vec<u8>)> {
+    let compression = choose(data, preferred);
+    Ok((compression, compress(data, compression)?))
+}
+
+/// Wraps a reader of the encoded body so it yields the original bytes.
+pub fn decoder<'a>(reader: impl Read + 'a, compression: Compression) -> io::Result<Box<dyn Read + 'a>> {
+    Ok(match compression {
+        Compression::None => Box::new(reader),
+        Compression::Zstd => Box::new(zstd::Decoder::new(reader)?),
+        Compression::Lz4 => Box::new(lz4_flex::frame::FrameDecoder::new(reader)),
+    })
+}
+
+pub fn decompress(data: &[u8], compression: Compression) -> io::Result<Vec<u8>> {
+    let mut out = vec![];
+    decoder(data, compression)?.read_to_end(&mut out)?;
+    Ok(out)
+}
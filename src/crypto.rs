@@ -0,0 +1,153 @@
+//! Client side encryption, so the server only ever stores ciphertext.
+
+use std::{fmt::Debug, fs::File, io::{self, Write}, path::Path};
+
+use openssl::{hash::MessageDigest, pkcs5::pbkdf2_hmac, pkey::PKey, rand::rand_bytes, sha::sha512, sign::Signer, symm::{decrypt_aead, encrypt_aead, Cipher}};
+
+/// marks the start of an encrypted file body
+const MAGIC: &[u8; 4] = b"NASE";
+const NONCE_LEN: usize = 12;
+const TAG_LEN: usize = 16;
+/// how much longer [`Key::encrypt`] makes its input
+pub const OVERHEAD: u64 = (MAGIC.len() + NONCE_LEN + TAG_LEN) as u64;
+const PBKDF2_ROUNDS: usize = 600_000;
+/// of the salt a new keyfile gets, also enough for it to work as a key on its own
+const SALT_LEN: usize = 32;
+/// longest name that still fits in 255 bytes once encrypted
+pub const MAX_NAME_LEN: usize = 255 * 3 / 4 - NONCE_LEN - TAG_LEN;
+
+/// Keys derived from a passphrase or keyfile.
+#[derive(Clone)]
+pub struct Key {
+    content: [u8; 32],
+    names: [u8; 32],
+    encrypt_names: bool,
+}
+impl Debug for Key {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Key").field("encrypt_names", &self.encrypt_names).finish_non_exhaustive()
+    }
+}
+impl Key {
+    /// The salt is whatever's in `keyfile`, which gets random bytes if it
+    /// doesn't exist yet. It has to be copied along to every machine using
+    /// the passphrase.
+    pub fn from_passphrase(passphrase: &str, keyfile: &Path, encrypt_names: bool) -> io::Result<Self> {
+        let salt = match std::fs::read(keyfile) {
+            Ok(salt) => salt,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                let mut salt = vec![0; SALT_LEN];
+                rand_bytes(&mut salt).map_err(io::Error::other)?;
+                File::create_new(keyfile)?.write_all(&salt)?;
+                salt
+            },
+            Err(err) => return Err(err),
+        };
+        if salt.len() < 16 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "keyfile is shorter than 16 bytes"));
+        }
+        let mut bytes = [0; 64];
+        pbkdf2_hmac(passphrase.as_bytes(), &salt, PBKDF2_ROUNDS, MessageDigest::sha256(), &mut bytes).map_err(io::Error::other)?;
+        Ok(Self::from_bytes(bytes, encrypt_names))
+    }
+    /// The keyfile should hold plenty of random bytes, it isn't stretched.
+    pub fn from_keyfile(path: &Path, encrypt_names: bool) -> io::Result<Self> {
+        let contents = std::fs::read(path)?;
+        if contents.len() < 32 {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "keyfile is shorter than 32 bytes"));
+        }
+        Ok(Self::from_bytes(sha512(&contents), encrypt_names))
+    }
+    fn from_bytes(bytes: [u8; 64], encrypt_names: bool) -> Self {
+        let (content, names) = bytes.split_at(32);
+        Self { content: content.try_into().unwrap(), names: names.try_into().unwrap(), encrypt_names }
+    }
+
+    /// Whether names are encrypted too, the server can't tell what they are then.
+    pub fn encrypts_names(&self) -> bool {
+        self.encrypt_names
+    }
+    pub fn encrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut nonce = [0; NONCE_LEN];
+        rand_bytes(&mut nonce).map_err(io::Error::other)?;
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&nonce);
+        out.extend(self.seal(&nonce, data)?);
+        Ok(out)
+    }
+    pub fn decrypt(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        let data = data.strip_prefix(MAGIC)
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "file isn't encrypted"))?;
+        self.open(data)
+    }
+
+    /// Encrypts every component of a `/` separated path if name encryption is on.
+    /// The same name always encrypts to the same string so paths can be looked up.
+    pub fn encrypt_path(&self, path: &str) -> io::Result<String> {
+        if !self.encrypt_names || path == "." {
+            return Ok(path.to_string());
+        }
+        let parts = path.split('/')
+            .map(|x| self.encrypt_name(x))
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(parts.join("/"))
+    }
+    pub fn encrypt_name(&self, name: &str) -> io::Result<String> {
+        if !self.encrypt_names {
+            return Ok(name.to_string());
+        }
+        if name.len() > MAX_NAME_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidFilename, format!("{name} is too long to encrypt, names can have at most {MAX_NAME_LEN} bytes")));
+        }
+        // synthetic nonce, so encryption is deterministic
+        let mac_key = PKey::hmac(&self.names).map_err(io::Error::other)?;
+        let mut signer = Signer::new(MessageDigest::sha256(), &mac_key).map_err(io::Error::other)?;
+        signer.update(name.as_bytes()).map_err(io::Error::other)?;
+        let nonce = &signer.sign_to_vec().map_err(io::Error::other)?[..NONCE_LEN];
+        let mut out = nonce.to_vec();
+        out.extend(self.seal(nonce, name.as_bytes())?);
+        Ok(base64url_encode(&out))
+    }
+    /// Names that weren't encrypted with this key are returned as is.
+    pub fn decrypt_name(&self, name: &str) -> String {
+        if !self.encrypt_names {
+            return name.to_string();
+        }
+        base64url_decode(name)
+            .and_then(|x| self.open(&x).ok())
+            .and_then(|x| String::from_utf8(x).ok())
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    fn seal(&self, nonce: &[u8], data: &[u8]) -> io::Result<Vec<u8>> {
+        let mut tag = [0; TAG_LEN];
+        let mut out = encrypt_aead(Cipher::aes_256_gcm(), &self.content, Some(nonce), &[], data, &mut tag).map_err(io::Error::other)?;
+        out.extend_from_slice(&tag);
+        Ok(out)
+    }
+    /// `data` is the nonce, ciphertext then tag
+    fn open(&self, data: &[u8]) -> io::Result<Vec<u8>> {
+        if data.len() < NONCE_LEN + TAG_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "ciphertext too short"));
+        }
+        let (nonce, rest) = data.split_at(NONCE_LEN);
+        let (ciphertext, tag) = rest.split_at(rest.len() - TAG_LEN);
+        decrypt_aead(Cipher::aes_256_gcm(), &self.content, Some(nonce), &[], ciphertext, tag)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "wrong key or corrupted data"))
+    }
+}
+
+/// base64 without padding and with a file name safe alphabet
+fn base64url_encode(data: &[u8]) -> String {
+    openssl::base64::encode_block(data)
+        .trim_end_matches('=')
+        .replace('+', "-")
+        .replace('/', "_")
+}
+fn base64url_decode(data: &str) -> Option<Vec<u8>> {
+    let mut data = data.replace('-', "+").replace('_', "/");
+    while !data.len().is_multiple_of(4) {
+        data.push('=');
+    }
+    openssl::base64::decode_block(&data).ok()
+}
@@ -0,0 +1,406 @@
+//! Content addressed storage: files are split into content defined chunks,
+//! every chunk is stored once under its hash and the file itself only holds
+//! a manifest listing its chunks.
+
+use std::{collections::HashSet, fs::File, io::{self, Read, Write}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, RwLock}};
+
+use openssl::sha::sha256;
+use rkyv::{rancor, util::AlignedVec, Archive, Deserialize, Serialize};
+
+use crate::storage::{write_atomic, LocalBackend, Metadata, StorageBackend};
+
+/// marks a file as a manifest rather than plain contents
+const MAGIC: &[u8; 8] = b"NASDEDUP";
+const MIN_CHUNK: usize = 16 * 1024;
+const MAX_CHUNK: usize = 256 * 1024;
+/// gives an average chunk of about 64KiB
+const BOUNDARY_MASK: u64 = (1 << 16) - 1;
+
+pub type Hash = [u8; 32];
+
+#[derive(Serialize, Deserialize, Archive, Clone, Debug)]
+pub struct Manifest {
+    pub len: u64,
+    pub chunks: Vec<Hash>,
+}
+
+/// Totals over every manifest and chunk.
+#[derive(Default, Clone, Copy, Debug)]
+pub struct DedupStats {
+    /// size of the files as the clients see them
+    pub logical_bytes: u64,
+    /// size of the chunks actually on disk
+    pub stored_bytes: u64,
+    pub chunks: u64,
+    /// chunks removed by the last collection
+    pub collected: u64,
+    /// files that vanished or couldn't be read during the last collection,
+    /// chunks only they refer to are collected too
+    pub skipped: u64,
+}
+
+pub struct ChunkStore {
+    root: PathBuf,
+    /// writers share it, garbage collection takes it exclusively so it never
+    /// sees chunks whose manifest isn't written yet
+    gc_lock: RwLock<()>,
+}
+impl ChunkStore {
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(Self { root, gc_lock: RwLock::new(()) })
+    }
+
+    fn chunk_path(&self, hash: &Hash) -> PathBuf {
+        let hex: String = hash.iter().map(|x| format!("{x:02x}")).collect();
+        self.root.join(&hex[..2]).join(&hex[2..])
+    }
+
+    /// Stores the data from `reader` and writes its manifest to `path`.
+    /// The manifest is only written once every chunk is stored.
+    pub fn write(&self, reader: &mut dyn Read, path: &Path) -> io::Result<Manifest> {
+        let _guard = self.gc_lock.read().unwrap_or_else(|x| x.into_inner());
+        let mut manifest = Manifest { len: 0, chunks: vec![] };
+        let mut chunker = Chunker::new(reader);
+        while let Some(chunk) = chunker.next_chunk()? {
+            let hash = sha256(chunk);
+            self.store_chunk(&hash, chunk)?;
+            manifest.len += chunk.len() as u64;
+            manifest.chunks.push(hash);
+        }
+        let bytes = rkyv::to_bytes::<rancor::Error>(&manifest).map_err(io::Error::other)?;
+        write_atomic(path, |file| {
+            file.write_all(MAGIC)?;
+            file.write_all(&bytes)
+        })?;
+        Ok(manifest)
+    }
+    fn store_chunk(&self, hash: &Hash, data: &[u8]) -> io::Result<()> {
+        let path = self.chunk_path(hash);
+        if path.exists() {
+            return Ok(());
+        }
+        std::fs::create_dir_all(path.parent().unwrap())?;
+        // written aside and renamed so a crash never leaves a short chunk under its hash
+        static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+        let tmp = path.with_extension(format!("{}.tmp", TMP_COUNTER.fetch_add(1, Ordering::Relaxed)));
+        std::fs::write(&tmp, data)?;
+        std::fs::rename(tmp, path)
+    }
+
+    /// Reads the file at `path`, following its manifest if it has one.
+    pub fn read(&self, path: &Path) -> io::Result<Vec<u8>> {
+        let Some(manifest) = read_manifest(path)? else {
+            return std::fs::read(path);
+        };
+        let mut out = Vec::with_capacity(manifest.len as usize);
+        for hash in &manifest.chunks {
+            let chunk = std::fs::read(self.chunk_path(hash))?;
+            if sha256(&chunk) != *hash {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "corrupted chunk"));
+            }
+            out.extend_from_slice(&chunk);
+        }
+        Ok(out)
+    }
+
+    /// Logical size of the file at `path`.
+    pub fn file_len(&self, path: &Path) -> io::Result<u64> {
+        match read_manifest(path)? {
+            Some(manifest) => Ok(manifest.len),
+            None => Ok(path.metadata()?.len()),
+        }
+    }
+
+    /// Deletes every chunk that no manifest under `files` refers to.
+    pub fn collect_garbage(&self, files: &Path) -> io::Result<DedupStats> {
+        let _guard = self.gc_lock.write().unwrap_or_else(|x| x.into_inner());
+        let mut stats = DedupStats::default();
+        let mut referenced = HashSet::new();
+        for path in walk_files(files)? {
+            // one broken file mustn't keep every other chunk from being collected
+            match read_manifest(&path).and_then(|x| Ok((x, path.metadata()?.len()))) {
+                Ok((Some(manifest), _)) => {
+                    stats.logical_bytes += manifest.len;
+                    referenced.extend(manifest.chunks.into_iter().map(|x| self.chunk_path(&x)));
+                },
+                Ok((None, len)) => stats.logical_bytes += len,
+                Err(_) => stats.skipped += 1,
+            }
+        }
+        for path in walk_files(&self.root)? {
+            if referenced.contains(&path) {
+                stats.stored_bytes += path.metadata()?.len();
+                stats.chunks += 1;
+            } else {
+                std::fs::remove_file(path)?;
+                stats.collected += 1;
+            }
+        }
+        Ok(stats)
+    }
+}
+
+/// A [`LocalBackend`] directory tree whose files are manifests into a [`ChunkStore`].
+/// Plain files already in the tree are still read as they are.
+pub struct DedupBackend {
+    files: LocalBackend,
+    store: ChunkStore,
+}
+impl DedupBackend {
+    pub fn new(files: impl Into<PathBuf>, chunks: impl Into<PathBuf>) -> io::Result<Self> {
+        Ok(Self { files: LocalBackend::new(files)?, store: ChunkStore::new(chunks)? })
+    }
+    pub fn collect_garbage(&self) -> io::Result<DedupStats> {
+        self.store.collect_garbage(self.files.root())
+    }
+    fn fix_len(&self, path: &Path, mut metadata: Metadata) -> io::Result<Metadata> {
+        if !metadata.is_dir {
+            metadata.len = self.store.file_len(path)?;
+        }
+        Ok(metadata)
+    }
+}
+impl StorageBackend for DedupBackend {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        self.store.read(&self.files.resolve(path)?)
+    }
+    fn write(&self, path: &str, data: &mut dyn Read) -> io::Result<u64> {
+        // chunks of a failed write are left for the garbage collector
+        Ok(self.store.write(data, &self.files.resolve_file(path)?)?.len)
+    }
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        self.files.mkdir(path)
+    }
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Metadata)>> {
+        let dir = self.files.resolve(path)?;
+        self.files.list(path)?
+            .into_iter()
+            .map(|(name, metadata)| Ok((name.clone(), self.fix_len(&dir.join(name), metadata)?)))
+            .collect()
+    }
+    fn delete(&self, path: &str) -> io::Result<()> {
+        let _guard = self.store.gc_lock.read().unwrap_or_else(|x| x.into_inner());
+        self.files.delete(path)
+    }
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        self.fix_len(&self.files.resolve(path)?, self.files.stat(path)?)
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        // a manifest moved during a collection could be missed by it
+        let _guard = self.store.gc_lock.read().unwrap_or_else(|x| x.into_inner());
+        self.files.rename(from, to)
+    }
+    fn abandon_write(&self, path: &str) {
+        self.files.abandon_write(path)
+    }
+    fn local_root(&self) -> Option<&Path> {
+        // the manifests have the names, their changes are the files' changes
+        Some(self.files.root())
+    }
+    fn clean_up(&self) -> io::Result<u64> {
+        // half-written chunks are never referenced, garbage collection takes them
+        self.files.clean_up()
+    }
+    fn used_bytes(&self) -> io::Result<u64> {
+        // the chunks plus the manifests, not the sizes the manifests stand for
+        let files = walk_files(self.files.root())?.into_iter().chain(walk_files(&self.store.root)?);
+        Ok(files.filter_map(|x| x.metadata().ok()).map(|x| x.len()).sum())
+    }
+}
+
+/// `None` if the file holds plain contents.
+fn read_manifest(path: &Path) -> io::Result<Option<Manifest>> {
+    let mut file = File::open(path)?;
+    let mut head = vec![];
+    (&mut file).take(MAGIC.len() as u64).read_to_end(&mut head)?;
+    if head != MAGIC {
+        return Ok(None);
+    }
+    let mut bytes = vec![];
+    file.read_to_end(&mut bytes)?;
+    let mut aligned = AlignedVec::<16>::with_capacity(bytes.len());
+    aligned.extend_from_slice(&bytes);
+    rkyv::from_bytes::<Manifest, rancor::Error>(&aligned)
+        .map(Some)
+        .map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))
+}
+
+/// Every regular file below `root`, skipping symlinks.
+fn walk_files(root: &Path) -> io::Result<Vec<PathBuf>> {
+    let mut out = vec![];
+    let mut stack = vec![root.to_path_buf()];
+    while let Some(dir) = stack.pop() {
+        let entries = match dir.read_dir() {
+            Ok(entries) => entries,
+            // deleted since it was listed
+            Err(err) if err.kind() == io::ErrorKind::NotFound && dir != root => continue,
+            Err(err) => return Err(err),
+        };
+        for entry in entries {
+            let entry = entry?;
+            let file_type = entry.file_type()?;
+            if file_type.is_dir() {
+                stack.push(entry.path());
+            } else if file_type.is_file() {
+                out.push(entry.path());
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Splits a stream at positions picked by a gear rolling hash, so an insert
+/// only changes the chunks around it.
+struct Chunker<R: Read> {
+    reader: R,
+    buf: Vec<u8>,
+    /// start of the unconsumed data in `buf`
+    start: usize,
+    eof: bool,
+}
+impl<R: Read> Chunker<R> {
+    fn new(reader: R) -> Self {
+        Self { reader, buf: Vec::with_capacity(MAX_CHUNK * 2), start: 0, eof: false }
+    }
+    fn next_chunk(&mut self) -> io::Result<Option<&[u8]>> {
+        self.buf.drain(..self.start);
+        self.start = 0;
+        while !self.eof && self.buf.len() < MAX_CHUNK {
+            let len = self.buf.len();
+            self.buf.resize(MAX_CHUNK, 0);
+            let read = self.reader.read(&mut self.buf[len..])?;
+            self.buf.truncate(len + read);
+            self.eof = read == 0;
+        }
+        if self.buf.is_empty() {
+            return Ok(None);
+        }
+        let end = boundary(&self.buf);
+        self.start = end;
+        Ok(Some(&self.buf[..end]))
+    }
+}
+
+fn boundary(data: &[u8]) -> usize {
+    if data.len() <= MIN_CHUNK {
+        return data.len();
+    }
+    let mut hash = 0u64;
+    for (i, byte) in data.iter().enumerate().take(MAX_CHUNK).skip(MIN_CHUNK) {
+        hash = (hash << 1).wrapping_add(GEAR[*byte as usize]);
+        if hash & BOUNDARY_MASK == 0 {
+            return i + 1;
+        }
+    }
+    data.len().min(MAX_CHUNK)
+}
+
+/// random values for the rolling hash, fixed so chunk boundaries are stable
+const GEAR: [u64; 256] = {
+    let mut table = [0; 256];
+    let mut state = 0x6e61735f72735f31u64;
+    let mut i = 0;
+    while i < 256 {
+        // splitmix64
+        state = state.wrapping_add(0x9e3779b97f4a7c15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xbf58476d1ce4e5b9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94d049bb133111eb);
+        table[i] = z ^ (z >> 31);
+        i += 1;
+    }
+    table
+};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that don't repeat, so chunk boundaries only depend on the data.
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) as u8
+        }).collect()
+    }
+
+    /// An empty directory of its own under the system's temp directory.
+    fn scratch(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("nas_rs-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        std::fs::create_dir_all(root.join("files")).unwrap();
+        root
+    }
+
+    #[test]
+    fn chunk_store_round_trip() {
+        let root = scratch("chunks");
+        let store = ChunkStore::new(root.join("chunks")).unwrap();
+        let (files, path_a, path_b) = (root.join("files"), root.join("files/a"), root.join("files/b"));
+
+        let a = data(2 * 1024 * 1024, 1);
+        assert_eq!(store.write(&mut a.as_slice(), &path_a).unwrap().len, a.len() as u64);
+        assert_eq!(store.read(&path_a).unwrap(), a);
+        assert_eq!(store.file_len(&path_a).unwrap(), a.len() as u64);
+        let stats = store.collect_garbage(&files).unwrap();
+        assert!(stats.chunks > 1);
+        assert_eq!((stats.logical_bytes, stats.stored_bytes), (a.len() as u64, a.len() as u64));
+
+        // a copy with a change at the front only stores the chunks around it
+        let mut b = a.clone();
+        b[100] ^= 1;
+        store.write(&mut b.as_slice(), &path_b).unwrap();
+        assert_eq!(store.read(&path_b).unwrap(), b);
+        assert_eq!(store.collect_garbage(&files).unwrap().chunks, stats.chunks + 1);
+
+        std::fs::remove_file(&path_b).unwrap();
+        assert_eq!(store.collect_garbage(&files).unwrap().collected, 1);
+        assert_eq!(store.read(&path_a).unwrap(), a);
+
+        // files that were there before deduplication are read as they are
+        let plain = root.join("files/plain");
+        std::fs::write(&plain, b"plain").unwrap();
+        assert_eq!(store.read(&plain).unwrap(), b"plain");
+        assert_eq!(store.file_len(&plain).unwrap(), 5);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn backend_round_trip() {
+        let root = scratch("dedup");
+        let storage = DedupBackend::new(root.join("files"), root.join("chunks")).unwrap();
+        let a = data(1024 * 1024, 2);
+        storage.mkdir("dir").unwrap();
+        assert_eq!(storage.write("dir/a", &mut a.as_slice()).unwrap(), a.len() as u64);
+        assert_eq!(storage.read("dir/a").unwrap(), a);
+        // sizes are those of the files, not the manifests
+        assert_eq!(storage.stat("dir/a").unwrap().len, a.len() as u64);
+        assert_eq!(storage.list("dir").unwrap()[0].1.len, a.len() as u64);
+        storage.rename("dir/a", "b").unwrap();
+        assert_eq!(storage.read("b").unwrap(), a);
+        storage.delete("b").unwrap();
+        assert!(storage.collect_garbage().unwrap().collected > 0);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+
+    #[test]
+    fn broken_manifests_dont_stop_collection() {
+        let root = scratch("gc");
+        let store = ChunkStore::new(root.join("chunks")).unwrap();
+        let files = root.join("files");
+        store.write(&mut data(100_000, 3).as_slice(), &files.join("gone")).unwrap();
+        std::fs::remove_file(files.join("gone")).unwrap();
+        let mut broken = MAGIC.to_vec();
+        broken.extend_from_slice(b"not a manifest");
+        std::fs::write(files.join("broken"), broken).unwrap();
+
+        let stats = store.collect_garbage(&files).unwrap();
+        assert_eq!(stats.skipped, 1);
+        assert!(stats.collected > 0);
+        assert_eq!(stats.chunks, 0);
+        std::fs::remove_dir_all(root).unwrap();
+    }
+}
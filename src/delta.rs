@@ -0,0 +1,268 @@
+//! rsync style delta transfer: the server describes the blocks of the file it
+//! has with a [`Signature`], the client answers with a [`Delta`] that reuses
+//! every block it also has and only spells out what's new.
+
+use std::{collections::HashMap, io};
+
+use openssl::sha::sha256;
+use rkyv::{Archive, Deserialize, Serialize};
+
+const MIN_BLOCK: usize = 1024;
+const MAX_BLOCK: usize = 128 * 1024;
+
+#[derive(Serialize, Deserialize, Archive, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BlockSum {
+    /// rsync's rolling checksum, cheap to compute at every offset
+    pub weak: u32,
+    pub strong: [u8; 32],
+}
+
+#[derive(Serialize, Deserialize, Archive, Clone, Debug, Default)]
+pub struct Signature {
+    /// of the whole file
+    pub len: u64,
+    pub block_len: u32,
+    /// the last block may be shorter than `block_len`
+    pub blocks: Vec<BlockSum>,
+}
+
+#[derive(Serialize, Deserialize, Archive, Clone, Debug, PartialEq, Eq)]
+pub enum Op {
+    /// `count` blocks of the old file starting at block `start`
+    Copy { start: u64, count: u64 },
+    Literal(Vec<u8>),
+}
+
+/// Body of a [`crate::Request::WriteDelta`].
+#[derive(Serialize, Deserialize, Archive, Clone, Debug)]
+pub struct Delta {
+    /// of the [`Signature`] the delta was made against
+    pub block_len: u32,
+    pub ops: Vec<Op>,
+    /// sha256 of the new file, checked after reconstructing it
+    pub hash: [u8; 32],
+}
+
+/// Roughly the square root of the length, so both the signature and the
+/// literal data around a change stay small.
+pub fn block_len(len: u64) -> u32 {
+    ((len as f64).sqrt() as usize).clamp(MIN_BLOCK, MAX_BLOCK) as u32
+}
+
+pub fn signature(data: &[u8]) -> Signature {
+    let block_len = block_len(data.len() as u64);
+    Signature {
+        len: data.len() as u64,
+        block_len,
+        blocks: data.chunks(block_len as usize)
+            .map(|x| BlockSum { weak: Rolling::new(x).sum(), strong: sha256(x) })
+            .collect(),
+    }
+}
+
+/// The adler-like checksum rsync uses, which can slide over the data a byte at a time.
+struct Rolling {
+    a: u32,
+    b: u32,
+    len: u32,
+}
+impl Rolling {
+    fn new(data: &[u8]) -> Self {
+        let mut rolling = Self { a: 0, b: 0, len: data.len() as u32 };
+        for (i, x) in data.iter().enumerate() {
+            rolling.a = rolling.a.wrapping_add(*x as u32);
+            rolling.b = rolling.b.wrapping_add((data.len() - i) as u32 * *x as u32);
+        }
+        rolling
+    }
+    /// Drops `old` from the front of the window and takes `new` at the back.
+    fn roll(&mut self, old: u8, new: u8) {
+        self.a = self.a.wrapping_sub(old as u32).wrapping_add(new as u32);
+        self.b = self.b.wrapping_sub(self.len.wrapping_mul(old as u32)).wrapping_add(self.a);
+    }
+    fn sum(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// What turns the file described by `signature` into `data`.
+pub fn diff(signature: &Signature, data: &[u8]) -> Delta {
+    let block_len = signature.block_len as usize;
+    let mut ops = vec![];
+    let mut literal = vec![];
+    let push_copy = |ops: &mut Vec<Op>, literal: &mut Vec<u8>, block: u64| {
+        if !literal.is_empty() {
+            ops.push(Op::Literal(std::mem::take(literal)));
+        }
+        match ops.last_mut() {
+            Some(Op::Copy { start, count }) if *start + *count == block => *count += 1,
+            _ => ops.push(Op::Copy { start: block, count: 1 }),
+        }
+    };
+
+    let mut by_weak: HashMap<u32, Vec<usize>> = HashMap::new();
+    let full_blocks = signature.len / signature.block_len.max(1) as u64;
+    for (i, block) in signature.blocks.iter().enumerate().take(full_blocks as usize) {
+        by_weak.entry(block.weak).or_default().push(i);
+    }
+    let find = |window: &[u8], weak: u32| {
+        let candidates = by_weak.get(&weak)?;
+        let strong = sha256(window);
+        candidates.iter().copied().find(|x| signature.blocks[*x].strong == strong)
+    };
+
+    let mut pos = 0;
+    let mut rolling = None;
+    while block_len != 0 && pos + block_len <= data.len() {
+        let window = &data[pos..pos + block_len];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).sum();
+        if let Some(block) = find(window, weak) {
+            push_copy(&mut ops, &mut literal, block as u64);
+            pos += block_len;
+            rolling = None;
+            continue;
+        }
+        literal.push(data[pos]);
+        if let (Some(rolling), Some(next)) = (rolling.as_mut(), data.get(pos + block_len)) {
+            rolling.roll(data[pos], *next);
+        }
+        pos += 1;
+    }
+    // the old file's last block is usually short and can only match at the end
+    let tail = &data[pos..];
+    let last = signature.blocks.len().checked_sub(1);
+    match last.filter(|x| !tail.is_empty() && signature.blocks[*x].strong == sha256(tail)) {
+        Some(block) => push_copy(&mut ops, &mut literal, block as u64),
+        None => literal.extend_from_slice(tail),
+    }
+    if !literal.is_empty() {
+        ops.push(Op::Literal(literal));
+    }
+    Delta { block_len: signature.block_len, ops, hash: sha256(data) }
+}
+
+/// Rebuilds the new file from the old one and the delta. The new file may
+/// only be `max_growth` bytes bigger than the old one and the literal data
+/// together, as copying the same blocks over and over could make it any size.
+pub fn apply(base: &[u8], delta: &Delta, max_growth: u64) -> io::Result<Vec<u8>> {
+    let invalid = |x: &str| io::Error::new(io::ErrorKind::InvalidData, x.to_string());
+    if delta.block_len != block_len(base.len() as u64) {
+        return Err(invalid("file changed since its signature was taken"));
+    }
+    let block_len = delta.block_len as u64;
+    let literal: u64 = delta.ops.iter().map(|x| match x {
+        Op::Literal(data) => data.len() as u64,
+        Op::Copy { .. } => 0,
+    }).sum();
+    let max_len = (base.len() as u64).saturating_add(literal).saturating_add(max_growth);
+    let too_big = || invalid("the new file would be too big");
+    let mut out = vec![];
+    for op in &delta.ops {
+        match op {
+            Op::Copy { start, count } => {
+                let begin = start.checked_mul(block_len).ok_or_else(|| invalid("block out of range"))?;
+                let end = start.checked_add(*count).and_then(|x| x.checked_mul(block_len))
+                    .ok_or_else(|| invalid("block out of range"))?
+                    .min(base.len() as u64);
+                if begin >= end {
+                    return Err(invalid("block out of range"));
+                }
+                if out.len() as u64 + (end - begin) > max_len {
+                    return Err(too_big());
+                }
+                out.extend_from_slice(&base[begin as usize..end as usize]);
+            },
+            Op::Literal(data) if out.len() as u64 + data.len() as u64 > max_len => return Err(too_big()),
+            Op::Literal(data) => out.extend_from_slice(data),
+        }
+    }
+    if sha256(&out) != delta.hash {
+        return Err(invalid("file changed since its signature was taken"));
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Bytes that don't repeat, so blocks only match where they should.
+    fn data(len: usize, seed: u64) -> Vec<u8> {
+        let mut state = seed;
+        (0..len).map(|_| {
+            state = state.wrapping_mul(6364136223846793005).wrapping_add(1442695040888963407);
+            (state >> 33) as u8
+        }).collect()
+    }
+
+    #[test]
+    fn round_trip() {
+        let old = data(100_000, 1);
+        let mut new = old.clone();
+        new.splice(50_000..50_010, data(300, 2));
+        new.extend_from_slice(b"the end");
+        let delta = diff(&signature(&old), &new);
+        assert_eq!(apply(&old, &delta, 0).unwrap(), new);
+        let literal: usize = delta.ops.iter().map(|x| match x {
+            Op::Literal(data) => data.len(),
+            Op::Copy { .. } => 0,
+        }).sum();
+        // only the blocks around the change are sent
+        assert!(literal < 3 * block_len(old.len() as u64) as usize, "{literal} bytes sent");
+
+        for (old, new) in [(vec![], data(5000, 3)), (data(5000, 3), vec![]), (data(10, 4), data(10, 4))] {
+            assert_eq!(apply(&old, &diff(&signature(&old), &new), 0).unwrap(), new);
+        }
+    }
+
+    #[test]
+    fn copies_out_of_range_are_refused() {
+        let old = data(4096, 1);
+        let block_len = block_len(old.len() as u64);
+        let delta = |ops| Delta { block_len, ops, hash: sha256(&old) };
+        for ops in [
+            vec![Op::Copy { start: 4, count: 1 }],
+            vec![Op::Copy { start: 0, count: 0 }],
+            vec![Op::Copy { start: u64::MAX, count: 1 }],
+            vec![Op::Copy { start: 1, count: u64::MAX }],
+        ] {
+            assert_eq!(apply(&old, &delta(ops), 0).unwrap_err().to_string(), "block out of range");
+        }
+        // the short last block is fine
+        assert_eq!(apply(&old, &delta(vec![Op::Copy { start: 0, count: 5 }]), 0).unwrap(), old);
+    }
+
+    #[test]
+    fn a_changed_base_fails_the_hash() {
+        let old = data(10_000, 1);
+        let new = [&old[..], b"more"].concat();
+        let delta = diff(&signature(&old), &new);
+        let mut changed = old.clone();
+        changed[0] ^= 1;
+        assert_eq!(apply(&changed, &delta, 0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+        let mut delta = delta;
+        delta.hash[0] ^= 1;
+        assert!(apply(&old, &delta, 0).is_err());
+    }
+
+    #[test]
+    fn output_is_capped() {
+        let old = data(4096, 1);
+        let new = [&old[..], &old[..], &old[..]].concat();
+        let delta = Delta {
+            block_len: block_len(old.len() as u64),
+            ops: vec![Op::Copy { start: 0, count: 4 }; 3],
+            hash: sha256(&new),
+        };
+        assert_eq!(apply(&old, &delta, 0).unwrap_err().to_string(), "the new file would be too big");
+        assert_eq!(apply(&old, &delta, 2 * old.len() as u64).unwrap(), new);
+    }
+
+    #[test]
+    fn other_block_lengths_are_refused() {
+        let old = data(10_000, 1);
+        let mut delta = diff(&signature(&old), &old);
+        delta.block_len *= 2;
+        assert_eq!(apply(&old, &delta, 0).unwrap_err().kind(), io::ErrorKind::InvalidData);
+    }
+}
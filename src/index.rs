@@ -0,0 +1,247 @@
+//! Optional full-text index over the text files in the storage, so files can
+//! be found by the words in them.
+//!
+//! Every word maps to the files containing it. The index is kept in memory,
+//! saved to [`INDEX_PATH`] now and then and rebuilt from the files when it's
+//! missing or broken. Files count as text when they're valid utf-8 and at most
+//! [`MAX_FILE_LEN`] long, so client side encrypted files never are.
+
+use std::{collections::{BTreeSet, HashMap}, io, ops::Range, sync::{atomic::{AtomicBool, Ordering}, RwLock}};
+
+use rkyv::{rancor, util::AlignedVec, Archive, Deserialize, Serialize};
+
+use crate::{locks::key, search::{search, Query}, storage::StorageBackend, versions::mkdir_all, META_DIR};
+
+/// inside [`crate::META_DIR`]
+pub const INDEX_PATH: &str = ".nas_rs/index";
+/// longer files are left out
+pub const MAX_FILE_LEN: u64 = 16 * 1024 * 1024;
+/// bytes of context on either side of the first hit in a snippet
+const CONTEXT: usize = 60;
+
+#[derive(Serialize, Deserialize, Archive, Clone, Debug, PartialEq, Eq)]
+pub struct ContentHit {
+    pub path: String,
+    /// a piece of the file around the first word found
+    pub snippet: String,
+    /// byte ranges of the words found inside `snippet`
+    pub highlights: Vec<(u32, u32)>,
+}
+
+#[derive(Serialize, Deserialize, Archive, Default)]
+struct Inner {
+    files_by_word: HashMap<String, BTreeSet<String>>,
+    /// so a file's words can be dropped when it changes
+    words_by_file: HashMap<String, Vec<String>>,
+}
+impl Inner {
+    fn remove(&mut self, path: &str) {
+        let Some(words) = self.words_by_file.remove(path) else {
+            return;
+        };
+        for word in words {
+            if let Some(files) = self.files_by_word.get_mut(&word) {
+                files.remove(path);
+                if files.is_empty() {
+                    self.files_by_word.remove(&word);
+                }
+            }
+        }
+    }
+    fn insert(&mut self, path: String, text: &str) {
+        self.remove(&path);
+        let words: BTreeSet<String> = words(text).map(|(_, x)| x).collect();
+        for word in &words {
+            self.files_by_word.entry(word.clone()).or_default().insert(path.clone());
+        }
+        self.words_by_file.insert(path, words.into_iter().collect());
+    }
+}
+
+/// The lowercased words of `text` with where they are.
+fn words(text: &str) -> impl Iterator<Item = (Range<usize>, String)> + '_ {
+    text.char_indices()
+        .filter(|(i, c)| c.is_alphanumeric() && !text[..*i].chars().next_back().is_some_and(char::is_alphanumeric))
+        .map(|(start, _)| {
+            let len = text[start..].find(|c: char| !c.is_alphanumeric()).unwrap_or(text.len() - start);
+            (start..start + len, text[start..start + len].to_lowercase())
+        })
+        .filter(|(_, x)| (2..=64).contains(&x.chars().count()))
+}
+
+fn is_below(path: &str, dir: &str) -> bool {
+    dir.is_empty() || path == dir || path.strip_prefix(dir).is_some_and(|x| x.starts_with('/'))
+}
+
+/// The index of every text file in `storage`.
+fn build(storage: &dyn StorageBackend) -> io::Result<Inner> {
+    let mut files = vec![];
+    search(storage, ".", &Query::default(), |file| {
+        if !file.is_dir && file.len <= MAX_FILE_LEN {
+            files.push(file.name);
+        }
+        Ok(())
+    })?;
+    let mut inner = Inner::default();
+    for path in files {
+        if let Ok(text) = String::from_utf8(storage.read(&path)?) {
+            inner.insert(key(&path), &text);
+        }
+    }
+    Ok(inner)
+}
+
+#[derive(Default)]
+pub struct ContentIndex {
+    inner: RwLock<Inner>,
+    /// changed since it was last saved
+    dirty: AtomicBool,
+}
+impl ContentIndex {
+    /// The saved index, `None` if there's none yet.
+    pub fn load(storage: &dyn StorageBackend) -> io::Result<Option<Self>> {
+        let bytes = match storage.read(INDEX_PATH) {
+            Ok(bytes) => bytes,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut aligned = AlignedVec::<16>::with_capacity(bytes.len());
+        aligned.extend_from_slice(&bytes);
+        let inner = rkyv::from_bytes::<Inner, rancor::Error>(&aligned).map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))?;
+        Ok(Some(Self { inner: RwLock::new(inner), dirty: AtomicBool::new(false) }))
+    }
+
+    /// Indexes every file from scratch.
+    pub fn rebuild(storage: &dyn StorageBackend) -> io::Result<Self> {
+        Ok(Self { inner: RwLock::new(build(storage)?), dirty: AtomicBool::new(true) })
+    }
+
+    /// Throws the index away and indexes every file from scratch, say when
+    /// it's gotten out of step with the files. Changes made meanwhile wait
+    /// for it so none are lost.
+    pub fn rebuild_in_place(&self, storage: &dyn StorageBackend) -> io::Result<()> {
+        let mut inner = self.inner.write().unwrap();
+        *inner = build(storage)?;
+        self.dirty.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Writes the index if it changed since the last time.
+    pub fn save(&self, storage: &dyn StorageBackend) -> io::Result<()> {
+        if !self.dirty.swap(false, Ordering::SeqCst) {
+            return Ok(());
+        }
+        let bytes = rkyv::to_bytes::<rancor::Error>(&*self.inner.read().unwrap()).map_err(io::Error::other);
+        let result = bytes.and_then(|bytes| {
+            mkdir_all(storage, META_DIR)?;
+            storage.write(INDEX_PATH, &mut bytes.as_slice()).map(|_| ())
+        });
+        if result.is_err() {
+            self.dirty.store(true, Ordering::SeqCst);
+        }
+        result
+    }
+
+    /// Indexes the file at `path` again, or everything inside it for a directory.
+    pub fn update(&self, storage: &dyn StorageBackend, path: &str) -> io::Result<()> {
+        let metadata = match storage.stat(path) {
+            Ok(metadata) => metadata,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                self.remove(path);
+                return Ok(());
+            },
+            Err(err) => return Err(err),
+        };
+        if metadata.is_dir {
+            return search(storage, path, &Query::default(), |file| {
+                if !file.is_dir {
+                    self.update(storage, &file.name)?;
+                }
+                Ok(())
+            });
+        }
+        let path = key(path);
+        let text = match metadata.len <= MAX_FILE_LEN {
+            true => String::from_utf8(storage.read(&path)?).ok(),
+            false => None,
+        };
+        let mut inner = self.inner.write().unwrap();
+        match text {
+            Some(text) => inner.insert(path, &text),
+            None => inner.remove(&path),
+        }
+        self.dirty.store(true, Ordering::SeqCst);
+        Ok(())
+    }
+
+    /// Forgets `path` and everything inside it.
+    pub fn remove(&self, path: &str) {
+        let path = key(path);
+        let mut inner = self.inner.write().unwrap();
+        let gone: Vec<_> = inner.words_by_file.keys().filter(|x| is_below(x, &path)).cloned().collect();
+        for file in gone {
+            inner.remove(&file);
+        }
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Moves the entries of `from` and everything inside it along with a rename.
+    pub fn rename(&self, from: &str, to: &str) {
+        let (from, to) = (key(from), key(to));
+        let mut inner = self.inner.write().unwrap();
+        let moved: Vec<_> = inner.words_by_file.keys().filter(|x| is_below(x, &from)).cloned().collect();
+        for path in moved {
+            let words = inner.words_by_file.remove(&path).unwrap();
+            let new_path = format!("{to}{}", &path[from.len()..]);
+            for word in &words {
+                let files = inner.files_by_word.get_mut(word).unwrap();
+                files.remove(&path);
+                files.insert(new_path.clone());
+            }
+            inner.words_by_file.insert(new_path, words);
+        }
+        self.dirty.store(true, Ordering::SeqCst);
+    }
+
+    /// Hands every file below `root` containing all words of `text` to `found`.
+    pub fn query(&self, storage: &dyn StorageBackend, root: &str, text: &str, mut found: impl FnMut(ContentHit) -> io::Result<()>) -> io::Result<()> {
+        let wanted: BTreeSet<String> = words(text).map(|(_, x)| x).collect();
+        if wanted.is_empty() {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "no words to look for"));
+        }
+        let root = key(root);
+        let paths: Vec<String> = {
+            let inner = self.inner.read().unwrap();
+            let mut sets = wanted.iter().map(|x| inner.files_by_word.get(x));
+            let first = sets.next().flatten().cloned().unwrap_or_default();
+            sets.fold(first, |acc, x| x.map(|x| &acc & x).unwrap_or_default())
+                .into_iter()
+                .filter(|x| is_below(x, &root))
+                .collect()
+        };
+        for path in paths {
+            // the file may have changed or gone since it was indexed
+            let Some(text) = storage.read(&path).ok().and_then(|x| String::from_utf8(x).ok()) else {
+                continue;
+            };
+            let hits: Vec<_> = words(&text).filter(|(_, x)| wanted.contains(x)).map(|(range, _)| range).collect();
+            let Some(first) = hits.first() else {
+                continue;
+            };
+            let mut start = first.start.saturating_sub(CONTEXT);
+            while !text.is_char_boundary(start) {
+                start -= 1;
+            }
+            let mut end = (first.end + CONTEXT).min(text.len());
+            while !text.is_char_boundary(end) {
+                end += 1;
+            }
+            let highlights = hits.iter()
+                .filter(|x| x.start >= start && x.end <= end)
+                .map(|x| ((x.start - start) as u32, (x.end - start) as u32))
+                .collect();
+            found(ContentHit { path, snippet: text[start..end].to_string(), highlights })?;
+        }
+        Ok(())
+    }
+}
@@ -1,49 +1,269 @@
+pub mod compress;
+pub mod crypto;
+pub mod dedup;
+pub mod delta;
+pub mod index;
+pub mod locks;
+pub mod search;
+pub mod storage;
+pub mod thumbnail;
+pub mod trash;
+pub mod versions;
+
 use std::{io::{Read, Write}, path::{Path, PathBuf}};
 
+use compress::Compression;
+use locks::LockInfo;
+use search::SearchKind;
 use rkyv::{access, api::high::{HighDeserializer, HighSerializer}, deserialize, rancor, ser::allocator::ArenaHandle, to_bytes, util::AlignedVec, Archive, Deserialize, Portable, Serialize};
 
 pub const PORT: u16 = 4949;
 
 #[derive(Serialize, Deserialize, Archive, Clone, Debug)]
 pub enum Request {
+    /// `len` bytes of the body, encoded with `compression`, follow the request
     Write {
         path: String,
         len: u64,
+        compression: Compression,
     },
     MkDir {
         path: String,
     },
+    /// the server may encode the file with any of the `accept`ed compressions
     Read {
         path: String,
+        accept: Vec<Compression>,
     },
     EnumDir {
         path: String,
     },
+    /// moves the path into the client's trash
     Delete {
         path: String,
+    },
+    ListVersions {
+        path: String,
+    },
+    /// answered like [`Request::Read`]
+    ReadVersion {
+        path: String,
+        version: u64,
+        accept: Vec<Compression>,
+    },
+    RestoreVersion {
+        path: String,
+        version: u64,
+    },
+    /// answered like [`Request::Read`] with a png of the image that fits in
+    /// a `size` pixel square, see [`thumbnail::thumbnail`]
+    Thumbnail {
+        path: String,
+        size: u32,
+    },
+    /// answered with a [`delta::Signature`] of the file, empty if there's none
+    Signature {
+        path: String,
+    },
+    /// replaces the file with one rebuilt from it and the [`delta::Delta`]
+    /// making up the `len` byte body
+    WriteDelta {
+        path: String,
+        len: u64,
+        compression: Compression,
+    },
+    /// Keeps the connection open and reports changes below `path`: the server
+    /// sends 1 and a [`Change`] for every change, [`WATCH_KEEPALIVE`] now and
+    /// then to see whether the client is still there and 0 when it stops.
+    Watch {
+        path: String,
+        /// also changes in subdirectories, otherwise only the directory's own entries
+        recursive: bool,
+    },
+    /// Looks for files below `root` matching all of the criteria, see
+    /// [`search::Query`]. The server sends 1 and a [`FileInfo`] named with
+    /// the whole path for every match.
+    Search {
+        root: String,
+        pattern: String,
+        kind: SearchKind,
+        min_size: Option<u64>,
+        max_size: Option<u64>,
+        /// nanoseconds since the unix epoch
+        modified_after: Option<u64>,
+    },
+    /// Looks for text files below `root` containing every word of `query`.
+    /// The server sends 1 and an [`index::ContentHit`] for every match.
+    /// Fails when the server keeps no content index.
+    ContentSearch {
+        root: String,
+        query: String,
+    },
+    /// moves `path` to `to`, which must not exist yet
+    Rename {
+        path: String,
+        to: String,
+    },
+    /// copies `path`, a whole directory included, to `to`, which must not exist yet
+    Copy {
+        path: String,
+        to: String,
+    },
+    /// Takes or renews a lock for `lease_secs`, answered with the [`LockInfo`].
+    /// Fails while someone else's lock is in the way.
+    Lock {
+        path: String,
+        exclusive: bool,
+        lease_secs: u64,
+    },
+    /// gives up the client's own lock on `path`
+    Unlock {
+        path: String,
+    },
+    ListTrash,
+    RestoreTrash {
+        id: u64,
+    },
+    EmptyTrash,
+    /// builds the content index again from the files, answered once it's
+    /// done. Fails when the server keeps no content index.
+    RebuildIndex,
+}
+
+impl Request {
+    /// Name of the variant, for logging.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Request::Write { .. } => "write",
+            Request::MkDir { .. } => "mkdir",
+            Request::Read { .. } => "read",
+            Request::EnumDir { .. } => "enum_dir",
+            Request::Delete { .. } => "delete",
+            Request::ListVersions { .. } => "list_versions",
+            Request::ReadVersion { .. } => "read_version",
+            Request::RestoreVersion { .. } => "restore_version",
+            Request::Thumbnail { .. } => "thumbnail",
+            Request::Signature { .. } => "signature",
+            Request::WriteDelta { .. } => "write_delta",
+            Request::Watch { .. } => "watch",
+            Request::Search { .. } => "search",
+            Request::ContentSearch { .. } => "content_search",
+            Request::Rename { .. } => "rename",
+            Request::Copy { .. } => "copy",
+            Request::Lock { .. } => "lock",
+            Request::Unlock { .. } => "unlock",
+            Request::ListTrash => "list_trash",
+            Request::RestoreTrash { .. } => "restore_trash",
+            Request::EmptyTrash => "empty_trash",
+            Request::RebuildIndex => "rebuild_index",
+        }
+    }
+    /// Empty for requests that aren't about a path.
+    pub fn path(&self) -> &str {
+        match self {
+            Request::Write { path, .. }
+            | Request::MkDir { path }
+            | Request::Read { path, .. }
+            | Request::EnumDir { path }
+            | Request::Delete { path }
+            | Request::ListVersions { path }
+            | Request::ReadVersion { path, .. }
+            | Request::RestoreVersion { path, .. }
+            | Request::Thumbnail { path, .. }
+            | Request::Signature { path }
+            | Request::WriteDelta { path, .. }
+            | Request::Watch { path, .. }
+            | Request::Search { root: path, .. }
+            | Request::ContentSearch { root: path, .. }
+            | Request::Rename { path, .. }
+            | Request::Copy { path, .. }
+            | Request::Lock { path, .. }
+            | Request::Unlock { path } => path,
+            Request::ListTrash | Request::RestoreTrash { .. } | Request::EmptyTrash | Request::RebuildIndex => "",
+        }
+    }
+    /// Whether the request changes anything on the server.
+    pub fn is_mutating(&self) -> bool {
+        matches!(
+            self,
+            Request::Write { .. } | Request::WriteDelta { .. } | Request::MkDir { .. } | Request::Delete { .. }
+                | Request::Rename { .. } | Request::Copy { .. } | Request::Lock { .. } | Request::Unlock { .. }
+                | Request::RestoreVersion { .. } | Request::RestoreTrash { .. } | Request::EmptyTrash
+                | Request::RebuildIndex
+        )
     }
 }
 
 #[derive(Serialize, Deserialize, Archive, Clone, Debug)]
 pub struct FileRead {
     pub len: u64,
+    pub compression: Compression,
 }
 
 #[derive(Serialize, Deserialize, Archive, Clone, Debug)]
 pub struct DirEnum {
-    pub files: Vec<(String, bool)>,
+    pub files: Vec<FileInfo>,
+}
+
+#[derive(Serialize, Deserialize, Archive, Clone, Debug, PartialEq, Eq)]
+pub struct FileInfo {
+    pub name: String,
+    pub is_dir: bool,
+    /// zero for directories
+    pub len: u64,
+    /// nanoseconds since the unix epoch
+    pub modified: u64,
+    /// entries directly inside a directory, only filled in when listing one
+    pub entries: u64,
+    /// who holds a lock on it right now
+    pub locks: Vec<LockInfo>,
+}
+
+#[derive(Serialize, Deserialize, Archive, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ChangeKind {
+    Create,
+    Modify,
+    Delete,
+    /// `path` was moved to `to`
+    Rename,
+}
+
+/// Something that happened below a watched directory, see [`Request::Watch`].
+#[derive(Serialize, Deserialize, Archive, Clone, Debug, PartialEq, Eq)]
+pub struct Change {
+    pub kind: ChangeKind,
+    pub path: String,
+    /// only set for renames
+    pub to: Option<String>,
 }
 
+/// sent instead of a [`Change`] during a [`Request::Watch`] when nothing happened for a while
+pub const WATCH_KEEPALIVE: u64 = 2;
+
 pub const PATH: &str = "./files/";
+/// directory in the storage root where the server keeps its own data
+pub const META_DIR: &str = ".nas_rs";
+/// Whether `path` is inside [`META_DIR`], which clients can't touch.
+pub fn is_reserved(path: &str) -> bool {
+    path.split(['/', '\\']).find(|x| !x.is_empty() && *x != ".") == Some(META_DIR)
+}
 /// doesn't allow symlinks
 pub fn sanitize_path(path: &str) -> Option<PathBuf> {
+    sanitize_path_in(Path::new(PATH), path)
+}
+/// Whether `path` stays inside whatever root it's joined to.
+pub fn is_relative_path(path: &str) -> bool {
     if !path.is_empty() && (path.starts_with('/') || path.starts_with('\\')) {
-        return None;
+        return false;
     }
-    if Path::new(path).iter().any(|x| x == ".." || x == ".") || Path::new(path).is_absolute() {
+    !(Path::new(path).iter().any(|x| x == ".." || x == ".") || Path::new(path).is_absolute())
+}
+/// [`sanitize_path`] with another root, which has to exist
+pub fn sanitize_path_in(root: &Path, path: &str) -> Option<PathBuf> {
+    if !is_relative_path(path) {
         return None;
     }
-    let mut result = PathBuf::from(PATH).canonicalize().unwrap(); // shouldn't panic (hardcoded)
+    let mut result = root.canonicalize().ok()?;
     result.push(path);
     if result.is_symlink() {
         return None;
@@ -0,0 +1,194 @@
+//! Advisory locks with a lease, so clients can keep others from changing a
+//! file while they work on it.
+//!
+//! A lock is held by the client identity that took it. Any number of clients
+//! can share a path, an exclusive lock needs the path to itself. Either way
+//! nobody but the holders may write, delete or rename the path or anything
+//! below it. Locks only live in memory and are gone when the server restarts.
+
+use std::{collections::HashMap, io, sync::Mutex, time::Duration};
+
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::versions::now_nanos;
+
+/// longest lease handed out, clients renew by locking again
+pub const MAX_LEASE: Duration = Duration::from_secs(24 * 3600);
+
+#[derive(Serialize, Deserialize, Archive, Clone, Debug, PartialEq, Eq)]
+pub struct LockInfo {
+    /// client identity of the holder
+    pub owner: String,
+    pub exclusive: bool,
+    /// nanoseconds since the unix epoch when the lease runs out
+    pub expires: u64,
+}
+
+/// `path` without leading, trailing or `.` components.
+pub(crate) fn key(path: &str) -> String {
+    path.split('/').filter(|x| !x.is_empty() && *x != ".").collect::<Vec<_>>().join("/")
+}
+
+/// Whether one of the paths is the other or inside it.
+fn overlaps(a: &str, b: &str) -> bool {
+    let below = |x: &str, dir: &str| dir.is_empty() || x == dir || x.strip_prefix(dir).is_some_and(|x| x.starts_with('/'));
+    below(a, b) || below(b, a)
+}
+
+fn locked(owner: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::ResourceBusy, format!("locked by {owner}"))
+}
+
+#[derive(Default)]
+pub struct Locks {
+    /// by [`key`]
+    held: Mutex<HashMap<String, Vec<LockInfo>>>,
+}
+impl Locks {
+    /// Takes or renews the lock of `owner` on `path`, replacing a lock it
+    /// already holds there. Fails if someone else's lock is in the way.
+    pub fn lock(&self, owner: &str, path: &str, exclusive: bool, lease: Duration) -> io::Result<LockInfo> {
+        let path = key(path);
+        let now = now_nanos();
+        let mut held = self.held.lock().unwrap();
+        held.retain(|_, locks| {
+            locks.retain(|x| x.expires > now);
+            !locks.is_empty()
+        });
+        let conflict = held.iter()
+            .filter(|(x, _)| overlaps(x, &path))
+            .flat_map(|(x, locks)| locks.iter().map(move |lock| (x, lock)))
+            // shared locks on the same path get along, anything overlapping otherwise doesn't
+            .find(|(x, lock)| lock.owner != owner && (exclusive || lock.exclusive || **x != path));
+        if let Some((_, lock)) = conflict {
+            return Err(locked(&lock.owner));
+        }
+        let info = LockInfo {
+            owner: owner.to_string(),
+            exclusive,
+            expires: now.saturating_add(lease.min(MAX_LEASE).as_nanos() as u64),
+        };
+        let locks = held.entry(path).or_default();
+        locks.retain(|x| x.owner != owner);
+        locks.push(info.clone());
+        Ok(info)
+    }
+
+    pub fn unlock(&self, owner: &str, path: &str) -> io::Result<()> {
+        let path = key(path);
+        let mut held = self.held.lock().unwrap();
+        let locks = held.get_mut(&path).ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "not locked"))?;
+        let before = locks.len();
+        locks.retain(|x| x.owner != owner);
+        if locks.len() == before {
+            return Err(io::Error::new(io::ErrorKind::NotFound, "not locked by you"));
+        }
+        if locks.is_empty() {
+            held.remove(&path);
+        }
+        Ok(())
+    }
+
+    /// Moves the locks on `from` and anything inside it along with a rename.
+    pub fn rename(&self, from: &str, to: &str) {
+        let (from, to) = (key(from), key(to));
+        let mut held = self.held.lock().unwrap();
+        let moved: Vec<_> = held.keys().filter(|x| **x == from || x.strip_prefix(&from).is_some_and(|x| x.starts_with('/'))).cloned().collect();
+        for path in moved {
+            let locks = held.remove(&path).unwrap();
+            held.insert(format!("{to}{}", &path[from.len()..]), locks);
+        }
+    }
+
+    /// Fails if anyone but `owner` holds a lock on `path`, a directory above
+    /// it or anything inside it.
+    pub fn check(&self, owner: &str, path: &str) -> io::Result<()> {
+        let path = key(path);
+        let now = now_nanos();
+        let held = self.held.lock().unwrap();
+        let other = held.iter()
+            .filter(|(x, _)| overlaps(x, &path))
+            .flat_map(|(_, locks)| locks)
+            .find(|x| x.expires > now && x.owner != owner);
+        match other {
+            Some(lock) => Err(locked(&lock.owner)),
+            None => Ok(()),
+        }
+    }
+
+    /// The unexpired locks on `path`.
+    pub fn holders(&self, path: &str) -> Vec<LockInfo> {
+        let now = now_nanos();
+        self.held.lock().unwrap().get(&key(path))
+            .map(|locks| locks.iter().filter(|x| x.expires > now).cloned().collect())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const LEASE: Duration = Duration::from_secs(60);
+
+    #[test]
+    fn exclusive_locks_conflict() {
+        let locks = Locks::default();
+        locks.lock("alice", "docs/a", true, LEASE).unwrap();
+        let err = locks.lock("bob", "docs/a", false, LEASE).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ResourceBusy);
+        assert_eq!(err.to_string(), "locked by alice");
+        // directories above and paths below overlap, siblings don't
+        assert!(locks.lock("bob", "docs", false, LEASE).is_err());
+        assert!(locks.lock("bob", "docs/a/b", true, LEASE).is_err());
+        locks.lock("bob", "docs/ab", true, LEASE).unwrap();
+
+        assert!(locks.check("bob", "./docs/a/").is_err());
+        assert!(locks.check("bob", "docs").is_err());
+        locks.check("alice", "docs/a").unwrap();
+        locks.check("bob", "other").unwrap();
+    }
+
+    #[test]
+    fn shared_locks() {
+        let locks = Locks::default();
+        locks.lock("alice", "a", false, LEASE).unwrap();
+        locks.lock("bob", "a", false, LEASE).unwrap();
+        assert!(locks.lock("carol", "a", true, LEASE).is_err());
+        // taking it again replaces the old lock
+        locks.lock("alice", "a", false, LEASE).unwrap();
+        assert_eq!(locks.holders("a").len(), 2);
+        // nobody else may write while it's shared
+        assert!(locks.check("alice", "a").is_err());
+        assert!(locks.check("carol", "a").is_err());
+
+        locks.unlock("bob", "a").unwrap();
+        assert_eq!(locks.unlock("bob", "a").unwrap_err().kind(), io::ErrorKind::NotFound);
+        locks.check("alice", "a").unwrap();
+        locks.lock("alice", "a", true, LEASE).unwrap();
+        locks.unlock("alice", "a").unwrap();
+        assert!(locks.holders("a").is_empty());
+    }
+
+    #[test]
+    fn leases_expire() {
+        let locks = Locks::default();
+        locks.lock("alice", "a", true, Duration::ZERO).unwrap();
+        assert!(locks.holders("a").is_empty());
+        locks.check("bob", "a").unwrap();
+        locks.lock("bob", "a", true, LEASE).unwrap();
+
+        let info = locks.lock("carol", "b", true, Duration::MAX).unwrap();
+        assert!(info.expires <= now_nanos() + MAX_LEASE.as_nanos() as u64);
+    }
+
+    #[test]
+    fn locks_move_with_a_rename() {
+        let locks = Locks::default();
+        locks.lock("alice", "docs/a", true, LEASE).unwrap();
+        locks.rename("docs", "papers");
+        locks.check("bob", "docs/a").unwrap();
+        assert!(locks.check("bob", "papers/a").is_err());
+        assert_eq!(locks.holders("papers/a")[0].owner, "alice");
+    }
+}
@@ -0,0 +1,203 @@
+//! Finding files below a directory by name and attributes, for
+//! [`crate::Request::Search`].
+
+use std::{io, time::UNIX_EPOCH};
+
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::{is_reserved, storage::{Metadata, StorageBackend}, FileInfo};
+
+#[derive(Serialize, Deserialize, Archive, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum SearchKind {
+    #[default]
+    Any,
+    File,
+    Dir,
+}
+
+/// What a [`crate::Request::Search`] looks for, every part has to match.
+#[derive(Clone, Debug, Default)]
+pub struct Query {
+    /// glob with `*`, `?` and `[...]` matched against the name, ignoring case.
+    /// Without any of those it only has to appear somewhere in the name.
+    pub pattern: String,
+    pub kind: SearchKind,
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    /// nanoseconds since the unix epoch
+    pub modified_after: Option<u64>,
+}
+impl Query {
+    /// `file.name` may be a whole path, only its last part is matched.
+    pub fn matches(&self, file: &FileInfo) -> bool {
+        let name = file.name.rsplit('/').next().unwrap_or_default();
+        let kind = match self.kind {
+            SearchKind::Any => true,
+            SearchKind::File => !file.is_dir,
+            SearchKind::Dir => file.is_dir,
+        };
+        kind && self.min_size.is_none_or(|x| file.len >= x)
+            && self.max_size.is_none_or(|x| file.len <= x)
+            && self.modified_after.is_none_or(|x| file.modified > x)
+            && matches_name(&self.pattern, name)
+    }
+}
+
+fn matches_name(pattern: &str, name: &str) -> bool {
+    let pattern = pattern.to_lowercase();
+    let name = name.to_lowercase();
+    if !pattern.contains(['*', '?', '[']) {
+        return name.contains(&pattern);
+    }
+    glob(&pattern.chars().collect::<Vec<_>>(), &name.chars().collect::<Vec<_>>())
+}
+
+/// Tries every `*` on as few chars as it can and only ever goes back to the
+/// last one, so it takes at most pattern length times name length steps.
+fn glob(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // where the pattern goes on after the last `*` and where in the name that was tried
+    let mut star: Option<(usize, usize)> = None;
+    while n < name.len() {
+        if pattern.get(p) == Some(&'*') {
+            p += 1;
+            star = Some((p, n));
+            continue;
+        }
+        if p < pattern.len() {
+            let (matched, len) = matches_char(&pattern[p..], name[n]);
+            if matched {
+                p += len;
+                n += 1;
+                continue;
+            }
+        }
+        // let the last `*` take one more char
+        let Some((after, tried)) = star else {
+            return false;
+        };
+        star = Some((after, tried + 1));
+        (p, n) = (after, tried + 1);
+    }
+    pattern[p..].iter().all(|x| *x == '*')
+}
+
+/// Whether the token at the start of `pattern`, anything but a `*`, matches
+/// `c`, along with the length of the token.
+fn matches_char(pattern: &[char], c: char) -> (bool, usize) {
+    match pattern[0] {
+        '?' => (true, 1),
+        '[' => {
+            let Some(end) = pattern.iter().skip(2).position(|x| *x == ']').map(|x| x + 2) else {
+                // an unclosed bracket is just a bracket
+                return (c == '[', 1);
+            };
+            let (negated, set) = match pattern[1] {
+                '!' | '^' => (true, &pattern[2..end]),
+                _ => (false, &pattern[1..end]),
+            };
+            let mut found = false;
+            let mut i = 0;
+            while i < set.len() {
+                if i + 2 < set.len() && set[i + 1] == '-' {
+                    found |= (set[i]..=set[i + 2]).contains(&c);
+                    i += 3;
+                } else {
+                    found |= set[i] == c;
+                    i += 1;
+                }
+            }
+            (found != negated, end + 1)
+        },
+        x => (x == c, 1),
+    }
+}
+
+pub fn file_info(path: String, metadata: &Metadata) -> FileInfo {
+    FileInfo {
+        name: path,
+        is_dir: metadata.is_dir,
+        len: metadata.len,
+        modified: metadata.modified.duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64,
+        entries: 0,
+        locks: vec![],
+    }
+}
+
+/// Walks everything below `root` through the storage, so its rules about
+/// what can be reached apply, and hands each match to `found` with its
+/// whole path. The server's own data is skipped.
+pub fn search(storage: &dyn StorageBackend, root: &str, query: &Query, mut found: impl FnMut(FileInfo) -> io::Result<()>) -> io::Result<()> {
+    let root = root.trim_matches('/');
+    let mut stack = vec![if root == "." { String::new() } else { root.to_string() }];
+    while let Some(dir) = stack.pop() {
+        let mut entries = storage.list(if dir.is_empty() { "." } else { &dir })?;
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, metadata) in entries {
+            let path = if dir.is_empty() { name } else { format!("{dir}/{name}") };
+            if is_reserved(&path) {
+                continue;
+            }
+            if metadata.is_dir {
+                stack.push(path.clone());
+            }
+            let file = file_info(path, &metadata);
+            if query.matches(&file) {
+                found(file)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn glob_matching() {
+        assert!(matches_name("*.txt", "notes.txt"));
+        assert!(!matches_name("*.txt", "notes.txt.bak"));
+        assert!(matches_name("report-??.pdf", "report-07.pdf"));
+        assert!(!matches_name("report-??.pdf", "report-7.pdf"));
+        assert!(matches_name("img_[0-9]*", "IMG_2024.jpg"));
+        assert!(!matches_name("img_[!0-9]*", "img_2024.jpg"));
+        assert!(matches_name("a*b*c", "aXXbYYbc"));
+        assert!(matches_name("[ab", "[ab"));
+        assert!(matches_name("**", ""));
+        // without wildcards it's a substring search
+        assert!(matches_name("port", "Report.pdf"));
+    }
+
+    #[test]
+    fn glob_doesnt_backtrack_exponentially() {
+        let name = "a".repeat(200);
+        assert!(!matches_name(&format!("{}b", "*a".repeat(20)), &name));
+        assert!(matches_name(&"*a".repeat(20), &name));
+    }
+
+    #[test]
+    fn search_walks_the_storage() {
+        let storage = crate::storage::MemoryBackend::new();
+        for dir in ["docs", "docs/old", crate::META_DIR] {
+            storage.mkdir(dir).unwrap();
+        }
+        for (path, data) in [("a.txt", "x"), ("docs/b.txt", "xxxx"), ("docs/old/c.TXT", "xx"), ("docs/d.pdf", "x"), (".nas_rs/e.txt", "x")] {
+            storage.write(path, &mut data.as_bytes()).unwrap();
+        }
+        let find = |root: &str, query: Query| {
+            let mut found = vec![];
+            search(&storage, root, &query, |file| {
+                found.push(file.name);
+                Ok(())
+            }).unwrap();
+            found.sort();
+            found
+        };
+        let pattern = "*.txt".to_string();
+        assert_eq!(find(".", Query { pattern: pattern.clone(), ..Query::default() }), ["a.txt", "docs/b.txt", "docs/old/c.TXT"]);
+        assert_eq!(find("docs", Query { pattern: pattern.clone(), min_size: Some(2), ..Query::default() }), ["docs/b.txt", "docs/old/c.TXT"]);
+        assert_eq!(find("docs", Query { pattern, max_size: Some(2), ..Query::default() }), ["docs/old/c.TXT"]);
+        assert_eq!(find(".", Query { kind: SearchKind::Dir, ..Query::default() }), ["docs", "docs/old"]);
+    }
+}
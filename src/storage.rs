@@ -0,0 +1,494 @@
+//! Where the server keeps its files.
+//!
+//! Paths handed to a backend are relative to its root, use `/` as the
+//! separator and `.` (or an empty string) for the root itself.
+
+use std::{collections::BTreeMap, fs::File, io::{self, Read}, path::{Path, PathBuf}, sync::{atomic::{AtomicU64, Ordering}, Mutex}, time::SystemTime};
+
+use crate::{is_relative_path, is_reserved, locks::key, sanitize_path_in};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Metadata {
+    pub is_dir: bool,
+    /// zero for directories
+    pub len: u64,
+    pub modified: SystemTime,
+}
+
+pub trait StorageBackend: Send + Sync {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>>;
+    /// Replaces the file at `path` with everything `data` yields, returning
+    /// the number of bytes stored. A failed write leaves nothing behind.
+    fn write(&self, path: &str, data: &mut dyn Read) -> io::Result<u64>;
+    fn mkdir(&self, path: &str) -> io::Result<()>;
+    /// Entries of the directory at `path`, symlinks are left out.
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Metadata)>>;
+    /// Removes a file, or a directory with everything in it.
+    fn delete(&self, path: &str) -> io::Result<()>;
+    fn stat(&self, path: &str) -> io::Result<Metadata>;
+    fn rename(&self, from: &str, to: &str) -> io::Result<()>;
+    /// Called when the server exits while a write to `path` is still running,
+    /// so the backend can drop whatever that write left behind.
+    fn abandon_write(&self, _path: &str) {}
+    /// Removes what writes interrupted by a crash left behind, returning the
+    /// number of files removed. Called on startup.
+    fn clean_up(&self) -> io::Result<u64> {
+        Ok(0)
+    }
+    /// The directory the files are kept in under their own names, if there
+    /// is one, so changes made to it directly can be watched.
+    fn local_root(&self) -> Option<&Path> {
+        None
+    }
+    /// Bytes the stored data takes up, the server's own data included.
+    /// Looks at every file, so it's slow for big trees.
+    fn used_bytes(&self) -> io::Result<u64> {
+        let mut used = 0;
+        let mut stack = vec![String::new()];
+        while let Some(dir) = stack.pop() {
+            for (name, metadata) in self.list(if dir.is_empty() { "." } else { &dir })? {
+                match metadata.is_dir {
+                    true => stack.push(if dir.is_empty() { name } else { format!("{dir}/{name}") }),
+                    false => used += metadata.len,
+                }
+            }
+        }
+        Ok(used)
+    }
+}
+
+/// marks the files [`write_atomic`] writes to before renaming them
+const TEMP_SUFFIX: &str = ".nas_rs-tmp";
+
+/// A hidden file next to `path` that isn't used by anything else.
+fn temp_path(path: &Path) -> PathBuf {
+    static COUNTER: AtomicU64 = AtomicU64::new(0);
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{name}.{}{TEMP_SUFFIX}", COUNTER.fetch_add(1, Ordering::Relaxed)))
+}
+/// Whether `name` is a temporary file of an upload in progress.
+pub fn is_temp_file(name: &str) -> bool {
+    name.starts_with('.') && name.ends_with(TEMP_SUFFIX)
+}
+/// The name of the file that the temporary file `name` is renamed to once
+/// its upload is done.
+pub fn temp_file_target(name: &str) -> Option<&str> {
+    let (target, counter) = name.strip_prefix('.')?.strip_suffix(TEMP_SUFFIX)?.rsplit_once('.')?;
+    (!counter.is_empty() && counter.bytes().all(|x| x.is_ascii_digit())).then_some(target)
+}
+/// Whether `name` is one of the temporary files [`temp_path`] makes for `of`.
+fn is_temp_file_of(name: &str, of: &str) -> bool {
+    temp_file_target(name) == Some(of)
+}
+
+/// Copies the file or directory at `from` to `to` through the backend,
+/// leaving out the server's own data and uploads in progress.
+pub fn copy(storage: &dyn StorageBackend, from: &str, to: &str) -> io::Result<()> {
+    let (from_key, to_key) = (key(from), key(to));
+    if to_key.strip_prefix(&from_key).is_some_and(|x| x.is_empty() || x.starts_with('/')) {
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, "can't copy a directory into itself"));
+    }
+    if !storage.stat(from)?.is_dir {
+        return storage.write(to, &mut storage.read(from)?.as_slice()).map(|_| ());
+    }
+    storage.mkdir(to)?;
+    for (name, _) in storage.list(from)? {
+        let path = format!("{from_key}/{name}");
+        if is_reserved(&path) || is_temp_file(&name) {
+            continue;
+        }
+        copy(storage, &path, &format!("{to_key}/{name}"))?;
+    }
+    Ok(())
+}
+
+/// Has `write` fill a temporary file that replaces `path` once it's complete
+/// and synced, so readers only ever see the old or the new contents.
+pub(crate) fn write_atomic<T>(path: &Path, write: impl FnOnce(&mut File) -> io::Result<T>) -> io::Result<T> {
+    let temp = temp_path(path);
+    let result = File::create(&temp).and_then(|mut file| {
+        let out = write(&mut file)?;
+        file.sync_all()?;
+        Ok(out)
+    });
+    let result = result.and_then(|out| {
+        std::fs::rename(&temp, path)?;
+        // makes the rename itself durable, directories can't be opened everywhere
+        if let Ok(dir) = File::open(path.parent().unwrap_or(Path::new("."))) {
+            let _ = dir.sync_all();
+        }
+        Ok(out)
+    });
+    if result.is_err() {
+        let _ = std::fs::remove_file(temp);
+    }
+    result
+}
+
+fn not_allowed() -> io::Error {
+    io::Error::new(io::ErrorKind::PermissionDenied, "not allowed >:(")
+}
+
+fn is_root(path: &str) -> bool {
+    path.is_empty() || path == "."
+}
+
+/// Files in a directory on the local filesystem, what the server always did.
+pub struct LocalBackend {
+    root: PathBuf,
+}
+impl LocalBackend {
+    /// Creates `root` if it doesn't exist yet.
+    pub fn new(root: impl Into<PathBuf>) -> io::Result<Self> {
+        let root = root.into();
+        std::fs::create_dir_all(&root)?;
+        Ok(Self { root: root.canonicalize()? })
+    }
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+    /// The absolute path of `path`, refusing anything outside the root or a symlink.
+    pub fn resolve(&self, path: &str) -> io::Result<PathBuf> {
+        if is_root(path) {
+            return Ok(self.root.clone());
+        }
+        sanitize_path_in(&self.root, path).ok_or_else(not_allowed)
+    }
+    /// [`Self::resolve`] for a file to write, which the root can never be.
+    pub fn resolve_file(&self, path: &str) -> io::Result<PathBuf> {
+        if is_root(path) {
+            return Err(not_allowed());
+        }
+        self.resolve(path)
+    }
+}
+impl StorageBackend for LocalBackend {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        std::fs::read(self.resolve(path)?)
+    }
+    fn write(&self, path: &str, data: &mut dyn Read) -> io::Result<u64> {
+        write_atomic(&self.resolve_file(path)?, |file| io::copy(data, file))
+    }
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        std::fs::create_dir(self.resolve(path)?)
+    }
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Metadata)>> {
+        let mut contents = vec![];
+        for entry in self.resolve(path)?.read_dir()? {
+            let entry = entry?;
+            if entry.file_type()?.is_symlink() {
+                continue;
+            }
+            let name = entry.file_name().into_string()
+                .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "non utf-8 filename"))?;
+            if is_temp_file(&name) {
+                continue;
+            }
+            contents.push((name, metadata(&entry.metadata()?)?));
+        }
+        Ok(contents)
+    }
+    fn delete(&self, path: &str) -> io::Result<()> {
+        if is_root(path) {
+            return Err(not_allowed());
+        }
+        let path = self.resolve(path)?;
+        if path.is_dir() {
+            std::fs::remove_dir_all(path)
+        } else {
+            std::fs::remove_file(path)
+        }
+    }
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        metadata(&self.resolve(path)?.metadata()?)
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        if is_root(from) || is_root(to) {
+            return Err(not_allowed());
+        }
+        std::fs::rename(self.resolve(from)?, self.resolve(to)?)
+    }
+    fn abandon_write(&self, path: &str) {
+        let Ok(path) = self.resolve_file(path) else {
+            return;
+        };
+        let (Some(dir), Some(name)) = (path.parent(), path.file_name()) else {
+            return;
+        };
+        let name = name.to_string_lossy();
+        for entry in dir.read_dir().into_iter().flatten().flatten() {
+            if is_temp_file_of(&entry.file_name().to_string_lossy(), &name) {
+                let _ = std::fs::remove_file(entry.path());
+            }
+        }
+    }
+    fn local_root(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+    fn clean_up(&self) -> io::Result<u64> {
+        let mut removed = 0;
+        let mut stack = vec![self.root.clone()];
+        while let Some(dir) = stack.pop() {
+            for entry in dir.read_dir()? {
+                let entry = entry?;
+                let file_type = entry.file_type()?;
+                if file_type.is_dir() {
+                    stack.push(entry.path());
+                } else if file_type.is_file() && is_temp_file(&entry.file_name().to_string_lossy()) {
+                    std::fs::remove_file(entry.path())?;
+                    removed += 1;
+                }
+            }
+        }
+        Ok(removed)
+    }
+}
+
+fn metadata(metadata: &std::fs::Metadata) -> io::Result<Metadata> {
+    Ok(Metadata {
+        is_dir: metadata.is_dir(),
+        len: if metadata.is_dir() { 0 } else { metadata.len() },
+        modified: metadata.modified()?,
+    })
+}
+
+enum Node {
+    Dir { modified: SystemTime },
+    File { data: Vec<u8>, modified: SystemTime },
+}
+impl Node {
+    fn metadata(&self) -> Metadata {
+        match self {
+            Node::Dir { modified } => Metadata { is_dir: true, len: 0, modified: *modified },
+            Node::File { data, modified } => Metadata { is_dir: false, len: data.len() as u64, modified: *modified },
+        }
+    }
+}
+
+/// Keeps everything in memory, for tests and throwaway servers.
+#[derive(Default)]
+pub struct MemoryBackend {
+    /// keyed by normalized path, the root isn't stored
+    nodes: Mutex<BTreeMap<String, Node>>,
+}
+
+/// Checks the path and strips redundant separators.
+fn normalize(path: &str) -> io::Result<String> {
+    if is_root(path) {
+        return Ok(String::new());
+    }
+    if !is_relative_path(path) {
+        return Err(not_allowed());
+    }
+    Ok(path.split(['/', '\\']).filter(|x| !x.is_empty()).collect::<Vec<_>>().join("/"))
+}
+fn parent(path: &str) -> &str {
+    path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or("")
+}
+fn not_found() -> io::Error {
+    io::Error::from(io::ErrorKind::NotFound)
+}
+fn is_in(path: &str, dir: &str) -> bool {
+    path.strip_prefix(dir).is_some_and(|x| x.starts_with('/'))
+}
+
+impl MemoryBackend {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    /// Fails unless `path`'s parent is an existing directory.
+    fn check_parent(nodes: &BTreeMap<String, Node>, path: &str) -> io::Result<()> {
+        let parent = parent(path);
+        match nodes.get(parent) {
+            _ if parent.is_empty() => Ok(()),
+            Some(Node::Dir { .. }) => Ok(()),
+            Some(Node::File { .. }) => Err(io::Error::from(io::ErrorKind::NotADirectory)),
+            None => Err(not_found()),
+        }
+    }
+}
+impl StorageBackend for MemoryBackend {
+    fn read(&self, path: &str) -> io::Result<Vec<u8>> {
+        let path = normalize(path)?;
+        match self.nodes.lock().unwrap().get(&path) {
+            Some(Node::File { data, .. }) => Ok(data.clone()),
+            Some(Node::Dir { .. }) => Err(io::Error::from(io::ErrorKind::IsADirectory)),
+            None => Err(not_found()),
+        }
+    }
+    fn write(&self, path: &str, data: &mut dyn Read) -> io::Result<u64> {
+        let path = normalize(path)?;
+        if path.is_empty() {
+            return Err(not_allowed());
+        }
+        let mut buf = vec![];
+        data.read_to_end(&mut buf)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        Self::check_parent(&nodes, &path)?;
+        if let Some(Node::Dir { .. }) = nodes.get(&path) {
+            return Err(io::Error::from(io::ErrorKind::IsADirectory));
+        }
+        let len = buf.len() as u64;
+        nodes.insert(path, Node::File { data: buf, modified: SystemTime::now() });
+        Ok(len)
+    }
+    fn mkdir(&self, path: &str) -> io::Result<()> {
+        let path = normalize(path)?;
+        let mut nodes = self.nodes.lock().unwrap();
+        if path.is_empty() || nodes.contains_key(&path) {
+            return Err(io::Error::from(io::ErrorKind::AlreadyExists));
+        }
+        Self::check_parent(&nodes, &path)?;
+        nodes.insert(path, Node::Dir { modified: SystemTime::now() });
+        Ok(())
+    }
+    fn list(&self, path: &str) -> io::Result<Vec<(String, Metadata)>> {
+        let path = normalize(path)?;
+        let nodes = self.nodes.lock().unwrap();
+        match nodes.get(&path) {
+            _ if path.is_empty() => {},
+            Some(Node::Dir { .. }) => {},
+            Some(Node::File { .. }) => return Err(io::Error::from(io::ErrorKind::NotADirectory)),
+            None => return Err(not_found()),
+        }
+        Ok(nodes.iter()
+            .filter(|(key, _)| parent(key) == path)
+            .map(|(key, node)| (key.rsplit('/').next().unwrap().to_string(), node.metadata()))
+            .collect())
+    }
+    fn delete(&self, path: &str) -> io::Result<()> {
+        let path = normalize(path)?;
+        if path.is_empty() {
+            return Err(not_allowed());
+        }
+        let mut nodes = self.nodes.lock().unwrap();
+        nodes.remove(&path).ok_or_else(not_found)?;
+        nodes.retain(|key, _| !is_in(key, &path));
+        Ok(())
+    }
+    fn stat(&self, path: &str) -> io::Result<Metadata> {
+        let path = normalize(path)?;
+        if path.is_empty() {
+            return Ok(Metadata { is_dir: true, len: 0, modified: SystemTime::UNIX_EPOCH });
+        }
+        self.nodes.lock().unwrap().get(&path).map(Node::metadata).ok_or_else(not_found)
+    }
+    fn rename(&self, from: &str, to: &str) -> io::Result<()> {
+        let (from, to) = (normalize(from)?, normalize(to)?);
+        if from.is_empty() || to.is_empty() || is_in(&to, &from) {
+            return Err(not_allowed());
+        }
+        let mut nodes = self.nodes.lock().unwrap();
+        Self::check_parent(&nodes, &to)?;
+        let node = nodes.remove(&from).ok_or_else(not_found)?;
+        let children: Vec<_> = nodes.keys().filter(|key| is_in(key, &from)).cloned().collect();
+        nodes.retain(|key, _| !is_in(key, &to));
+        for key in children {
+            let child = nodes.remove(&key).unwrap();
+            nodes.insert(format!("{to}{}", &key[from.len()..]), child);
+        }
+        nodes.insert(to, node);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn memory_backend() {
+        let storage = MemoryBackend::new();
+        storage.mkdir("docs").unwrap();
+        assert_eq!(storage.write("docs/a.txt", &mut &b"hello"[..]).unwrap(), 5);
+        assert_eq!(storage.read("docs//a.txt").unwrap(), b"hello");
+        assert_eq!(storage.stat("docs/a.txt").unwrap().len, 5);
+        assert_eq!(storage.write("nowhere/a.txt", &mut &b""[..]).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(storage.mkdir("docs").unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(storage.read("../a.txt").unwrap_err().kind(), io::ErrorKind::PermissionDenied);
+
+        storage.rename("docs", "papers").unwrap();
+        let names: Vec<_> = storage.list("papers").unwrap().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, ["a.txt"]);
+        assert_eq!(storage.stat("docs/a.txt").unwrap_err().kind(), io::ErrorKind::NotFound);
+
+        storage.delete("papers").unwrap();
+        assert_eq!(storage.read("papers/a.txt").unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert!(storage.list(".").unwrap().is_empty());
+    }
+
+    /// An empty directory of its own under the system's temp directory.
+    fn scratch(name: &str) -> LocalBackend {
+        let root = std::env::temp_dir().join(format!("nas_rs-{name}-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&root);
+        LocalBackend::new(root).unwrap()
+    }
+
+    #[test]
+    fn write_atomic_keeps_the_old_contents_on_failure() {
+        let storage = scratch("atomic");
+        let path = storage.root().join("a");
+        write_atomic(&path, |file| io::Write::write_all(file, b"old")).unwrap();
+        let err = write_atomic(&path, |file| {
+            io::Write::write_all(file, b"half of the new")?;
+            Err::<(), _>(io::Error::other("connection lost"))
+        }).unwrap_err();
+        assert_eq!(err.to_string(), "connection lost");
+        assert_eq!(std::fs::read(&path).unwrap(), b"old");
+        // the temp file went with the failure
+        assert_eq!(storage.root().read_dir().unwrap().count(), 1);
+        std::fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[test]
+    fn copy_directories() {
+        let storage = MemoryBackend::new();
+        storage.mkdir("docs").unwrap();
+        storage.mkdir("docs/old").unwrap();
+        storage.write("docs/old/a.txt", &mut &b"hello"[..]).unwrap();
+        copy(&storage, "docs", "copy").unwrap();
+        assert_eq!(storage.read("copy/old/a.txt").unwrap(), b"hello");
+        assert_eq!(storage.read("docs/old/a.txt").unwrap(), b"hello");
+        assert_eq!(copy(&storage, "docs", "docs/old/inner").unwrap_err().kind(), io::ErrorKind::InvalidInput);
+        assert!(storage.stat("docs/old/inner").is_err());
+    }
+
+    #[test]
+    fn root_cant_be_written() {
+        let storage = scratch("root-write");
+        for path in ["", "."] {
+            let err = storage.write(path, &mut &b"data"[..]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::PermissionDenied);
+        }
+        // a temp file for the root would land next to it
+        let name = storage.root().file_name().unwrap().to_string_lossy().into_owned();
+        let outside = storage.root().parent().unwrap().read_dir().unwrap();
+        assert!(!outside.flatten().any(|x| is_temp_file_of(&x.file_name().to_string_lossy(), &name)));
+        std::fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[test]
+    fn abandon_write_only_takes_its_own_temp_files() {
+        let storage = scratch("abandon");
+        let ours = storage.root().join(format!(".a.7{TEMP_SUFFIX}"));
+        let theirs = storage.root().join(format!(".a.b.7{TEMP_SUFFIX}"));
+        std::fs::write(&ours, b"").unwrap();
+        std::fs::write(&theirs, b"").unwrap();
+        storage.abandon_write("a");
+        assert!(!ours.exists());
+        assert!(theirs.exists());
+        std::fs::remove_dir_all(storage.root()).unwrap();
+    }
+
+    #[test]
+    fn used_bytes_counts_everything() {
+        let storage = MemoryBackend::new();
+        for dir in ["docs", "docs/old", crate::META_DIR] {
+            storage.mkdir(dir).unwrap();
+        }
+        for (path, data) in [("a", "x"), ("docs/old/b", "xxxx"), (".nas_rs/c", "xx")] {
+            storage.write(path, &mut data.as_bytes()).unwrap();
+        }
+        assert_eq!(storage.used_bytes().unwrap(), 7);
+    }
+}
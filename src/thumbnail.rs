@@ -0,0 +1,53 @@
+//! Small previews of images, made on demand and cached under
+//! [`THUMBNAIL_DIR`] in the same storage backend.
+
+use std::io::{self, Cursor};
+
+use image::{ImageFormat, ImageReader};
+
+use crate::{storage::StorageBackend, versions::mkdir_all};
+
+/// inside [`crate::META_DIR`]
+pub const THUMBNAIL_DIR: &str = ".nas_rs/thumbnails";
+/// sizes asked for are clamped to these
+pub const MIN_SIZE: u32 = 16;
+pub const MAX_SIZE: u32 = 1024;
+/// bigger images aren't worth decoding for a thumbnail
+const MAX_IMAGE_LEN: u64 = 64 * 1024 * 1024;
+
+fn cache_dir(path: &str) -> String {
+    format!("{THUMBNAIL_DIR}/{}", path.trim_matches('/'))
+}
+
+/// A png of the image at `path` that fits in a `size` by `size` square,
+/// keeping the aspect ratio. Fails with `InvalidData` for anything that
+/// isn't an image it can decode.
+pub fn thumbnail(storage: &dyn StorageBackend, path: &str, size: u32) -> io::Result<Vec<u8>> {
+    let size = size.clamp(MIN_SIZE, MAX_SIZE);
+    let metadata = storage.stat(path)?;
+    if metadata.is_dir || metadata.len > MAX_IMAGE_LEN {
+        return Err(io::Error::new(io::ErrorKind::InvalidData, "not an image"));
+    }
+    let cached = format!("{}/{size}.png", cache_dir(path));
+    // a cached thumbnail is good as long as the image hasn't changed since
+    if storage.stat(&cached).is_ok_and(|x| x.modified >= metadata.modified) {
+        return storage.read(&cached);
+    }
+    let image = ImageReader::new(Cursor::new(storage.read(path)?))
+        .with_guessed_format()?
+        .decode()
+        .map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))?;
+    let mut png = vec![];
+    image.thumbnail(size, size)
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(io::Error::other)?;
+    mkdir_all(storage, &cache_dir(path))?;
+    storage.write(&cached, &mut png.as_slice())?;
+    Ok(png)
+}
+
+/// Drops the cached thumbnails of `path` and everything inside it, for
+/// when it's deleted or moved.
+pub fn forget(storage: &dyn StorageBackend, path: &str) {
+    let _ = storage.delete(&cache_dir(path));
+}
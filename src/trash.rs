@@ -0,0 +1,255 @@
+//! Deleted files and directories, kept per user under [`TRASH_DIR`] until they
+//! expire or the trash is emptied.
+//!
+//! Every deletion gets its own directory named after the time it happened,
+//! holding the deleted item, its history and the path it was deleted from.
+
+use std::{io, time::Duration};
+
+use openssl::sha::sha256;
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::{storage::StorageBackend, versions::{mkdir_all, move_if_exists, now_nanos, version_dir}};
+
+/// inside [`crate::META_DIR`]
+pub const TRASH_DIR: &str = ".nas_rs/trash";
+/// name of the deleted file or directory inside its deletion's directory
+const ITEM: &str = "item";
+/// holds the path the item was deleted from
+const ORIGIN: &str = "origin";
+/// the earlier versions of the item, if it had any
+const VERSIONS: &str = "versions";
+
+#[derive(Serialize, Deserialize, Archive, Clone, Debug, PartialEq, Eq)]
+pub struct TrashItem {
+    /// nanoseconds since the unix epoch when the item was deleted
+    pub id: u64,
+    /// where the item was deleted from
+    pub path: String,
+    pub is_dir: bool,
+    /// zero for directories
+    pub len: u64,
+}
+
+#[derive(Serialize, Deserialize, Archive, Clone, Debug)]
+pub struct TrashList {
+    /// newest first
+    pub items: Vec<TrashItem>,
+}
+
+pub struct Trash {
+    /// `None` keeps deleted items until the trash is emptied
+    pub max_age: Option<Duration>,
+}
+impl Trash {
+    pub fn new(max_age: Option<Duration>) -> Self {
+        Self { max_age }
+    }
+
+    /// Named after a hash of the user name, which comes from a certificate
+    /// and may hold anything, so every name gets a directory of its own.
+    fn user_dir(user: &str) -> String {
+        let hex: String = sha256(user.as_bytes()).iter().map(|x| format!("{x:02x}")).collect();
+        format!("{TRASH_DIR}/{hex}")
+    }
+
+    /// Moves `path` and its history into the trash of `user`.
+    pub fn delete(&self, storage: &dyn StorageBackend, user: &str, path: &str) -> io::Result<()> {
+        storage.stat(path)?;
+        let dir = format!("{}/{}", Self::user_dir(user), now_nanos());
+        mkdir_all(storage, &dir)?;
+        let moved = storage.write(&format!("{dir}/{ORIGIN}"), &mut path.as_bytes())
+            .and_then(|_| move_if_exists(storage, &version_dir(path), &format!("{dir}/{VERSIONS}")));
+        if let Err(err) = moved.and_then(|_| storage.rename(path, &format!("{dir}/{ITEM}"))) {
+            let _ = move_if_exists(storage, &format!("{dir}/{VERSIONS}"), &version_dir(path));
+            let _ = storage.delete(&dir);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    pub fn list(&self, storage: &dyn StorageBackend, user: &str) -> io::Result<Vec<TrashItem>> {
+        let user_dir = Self::user_dir(user);
+        let entries = match storage.list(&user_dir) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut items = vec![];
+        for (name, _) in entries {
+            let Ok(id) = name.parse() else {
+                continue;
+            };
+            let dir = format!("{user_dir}/{name}");
+            // skips deletions that didn't finish
+            let (Ok(origin), Ok(metadata)) = (storage.read(&format!("{dir}/{ORIGIN}")), storage.stat(&format!("{dir}/{ITEM}"))) else {
+                continue;
+            };
+            items.push(TrashItem {
+                id,
+                path: String::from_utf8_lossy(&origin).into_owned(),
+                is_dir: metadata.is_dir,
+                len: metadata.len,
+            });
+        }
+        items.sort_by_key(|x| std::cmp::Reverse(x.id));
+        Ok(items)
+    }
+
+    /// The path item `id` was deleted from.
+    pub fn origin(&self, storage: &dyn StorageBackend, user: &str, id: u64) -> io::Result<String> {
+        let data = storage.read(&format!("{}/{id}/{ORIGIN}", Self::user_dir(user)))?;
+        String::from_utf8(data).map_err(|x| io::Error::new(io::ErrorKind::InvalidData, x))
+    }
+
+    /// Moves item `id` back where it was deleted from. Fails if something
+    /// has taken its place since. Returns the path it's back at.
+    pub fn restore(&self, storage: &dyn StorageBackend, user: &str, id: u64) -> io::Result<String> {
+        let dir = format!("{}/{id}", Self::user_dir(user));
+        let origin = self.origin(storage, user, id)?;
+        match storage.stat(&origin) {
+            Ok(_) => return Err(io::Error::new(io::ErrorKind::AlreadyExists, "something else is at the original path")),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {},
+            Err(err) => return Err(err),
+        }
+        if let Some((parent, _)) = origin.rsplit_once('/') {
+            mkdir_all(storage, parent)?;
+        }
+        move_if_exists(storage, &format!("{dir}/{VERSIONS}"), &version_dir(&origin))?;
+        if let Err(err) = storage.rename(&format!("{dir}/{ITEM}"), &origin) {
+            let _ = move_if_exists(storage, &version_dir(&origin), &format!("{dir}/{VERSIONS}"));
+            return Err(err);
+        }
+        storage.delete(&dir)?;
+        Ok(origin)
+    }
+
+    pub fn empty(&self, storage: &dyn StorageBackend, user: &str) -> io::Result<()> {
+        match storage.delete(&Self::user_dir(user)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    /// Deletes the items of every user that are older than `max_age`.
+    pub fn expire(&self, storage: &dyn StorageBackend) -> io::Result<()> {
+        let Some(max_age) = self.max_age else {
+            return Ok(());
+        };
+        let oldest = now_nanos().saturating_sub(max_age.as_nanos() as u64);
+        let users = match storage.list(TRASH_DIR) {
+            Ok(users) => users,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        for (user, _) in users {
+            for (name, _) in storage.list(&format!("{TRASH_DIR}/{user}"))? {
+                if name.parse::<u64>().is_ok_and(|x| x < oldest) {
+                    storage.delete(&format!("{TRASH_DIR}/{user}/{name}"))?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    #[test]
+    fn delete_and_restore() {
+        let storage = MemoryBackend::new();
+        let trash = Trash::new(None);
+        storage.mkdir("docs").unwrap();
+        storage.write("docs/a.txt", &mut &b"hello"[..]).unwrap();
+        trash.delete(&storage, "alice", "docs/a.txt").unwrap();
+        assert!(storage.stat("docs/a.txt").is_err());
+
+        let items = trash.list(&storage, "alice").unwrap();
+        assert_eq!(items.len(), 1);
+        assert_eq!((items[0].path.as_str(), items[0].is_dir, items[0].len), ("docs/a.txt", false, 5));
+        // everyone has a trash of their own
+        assert!(trash.list(&storage, "bob").unwrap().is_empty());
+        assert!(trash.restore(&storage, "bob", items[0].id).is_err());
+
+        assert_eq!(trash.origin(&storage, "alice", items[0].id).unwrap(), "docs/a.txt");
+        // the directory it was in comes back with it
+        storage.delete("docs").unwrap();
+        trash.restore(&storage, "alice", items[0].id).unwrap();
+        assert_eq!(storage.read("docs/a.txt").unwrap(), b"hello");
+        assert!(trash.list(&storage, "alice").unwrap().is_empty());
+    }
+
+    #[test]
+    fn history_goes_with_the_item() {
+        use crate::versions::{Retention, Versions};
+        let storage = MemoryBackend::new();
+        let trash = Trash::new(None);
+        let versions = Versions::new(Retention { max_count: 10, max_age: None });
+        for data in [b"1", b"2"] {
+            versions.preserve(&storage, "a").unwrap();
+            storage.write("a", &mut &data[..]).unwrap();
+        }
+        trash.delete(&storage, "alice", "a").unwrap();
+        assert!(versions.list(&storage, "a").unwrap().is_empty());
+
+        let id = trash.list(&storage, "alice").unwrap()[0].id;
+        trash.restore(&storage, "alice", id).unwrap();
+        let list = versions.list(&storage, "a").unwrap();
+        assert_eq!(versions.read(&storage, "a", list[0].id).unwrap(), b"1");
+    }
+
+    #[test]
+    fn restore_doesnt_overwrite() {
+        let storage = MemoryBackend::new();
+        let trash = Trash::new(None);
+        storage.write("a", &mut &b"old"[..]).unwrap();
+        trash.delete(&storage, "alice", "a").unwrap();
+        storage.write("a", &mut &b"new"[..]).unwrap();
+        let id = trash.list(&storage, "alice").unwrap()[0].id;
+        assert_eq!(trash.restore(&storage, "alice", id).unwrap_err().kind(), io::ErrorKind::AlreadyExists);
+        assert_eq!(storage.read("a").unwrap(), b"new");
+        assert_eq!(trash.list(&storage, "alice").unwrap().len(), 1);
+    }
+
+    #[test]
+    fn empty_and_expire() {
+        let storage = MemoryBackend::new();
+        let mut trash = Trash::new(None);
+        for (user, path) in [("alice", "a"), ("bob", "b"), ("carol", "c")] {
+            storage.write(path, &mut &b"x"[..]).unwrap();
+            trash.delete(&storage, user, path).unwrap();
+        }
+        trash.empty(&storage, "alice").unwrap();
+        assert!(trash.list(&storage, "alice").unwrap().is_empty());
+        assert_eq!(trash.list(&storage, "bob").unwrap().len(), 1);
+        // emptying an empty trash is fine
+        trash.empty(&storage, "alice").unwrap();
+
+        trash.expire(&storage).unwrap();
+        assert_eq!(trash.list(&storage, "carol").unwrap().len(), 1);
+        trash.max_age = Some(Duration::ZERO);
+        trash.expire(&storage).unwrap();
+        assert!(trash.list(&storage, "bob").unwrap().is_empty());
+        assert!(trash.list(&storage, "carol").unwrap().is_empty());
+    }
+
+    #[test]
+    fn users_with_similar_names_get_a_trash_each() {
+        let storage = MemoryBackend::new();
+        let trash = Trash::new(None);
+        let users = ["a b", "a_b", "", ".", ".."];
+        for (i, user) in users.iter().enumerate() {
+            let path = i.to_string();
+            storage.write(&path, &mut &b"x"[..]).unwrap();
+            trash.delete(&storage, user, &path).unwrap();
+        }
+        for (i, user) in users.iter().enumerate() {
+            let items = trash.list(&storage, user).unwrap();
+            assert_eq!(items.len(), 1);
+            assert_eq!(items[0].path, i.to_string());
+        }
+    }
+}
@@ -0,0 +1,241 @@
+//! Earlier contents of overwritten files, kept under [`VERSIONS_DIR`] in the
+//! same storage backend.
+
+use std::{io, time::{Duration, SystemTime, UNIX_EPOCH}};
+
+use rkyv::{Archive, Deserialize, Serialize};
+
+use crate::storage::StorageBackend;
+
+/// inside [`crate::META_DIR`]
+pub const VERSIONS_DIR: &str = ".nas_rs/versions";
+
+#[derive(Serialize, Deserialize, Archive, Clone, Debug, PartialEq, Eq)]
+pub struct VersionInfo {
+    /// nanoseconds since the unix epoch when the version was replaced
+    pub id: u64,
+    pub len: u64,
+}
+
+#[derive(Serialize, Deserialize, Archive, Clone, Debug)]
+pub struct VersionList {
+    /// newest first
+    pub versions: Vec<VersionInfo>,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct Retention {
+    /// versions kept per file, zero turns versioning off
+    pub max_count: usize,
+    pub max_age: Option<Duration>,
+}
+
+pub(crate) fn version_dir(path: &str) -> String {
+    format!("{VERSIONS_DIR}/{}", path.trim_matches('/'))
+}
+
+/// Creates `path` and all its parents.
+pub(crate) fn mkdir_all(storage: &dyn StorageBackend, path: &str) -> io::Result<()> {
+    let mut current = String::new();
+    for part in path.split('/').filter(|x| !x.is_empty()) {
+        if !current.is_empty() {
+            current.push('/');
+        }
+        current.push_str(part);
+        match storage.mkdir(&current) {
+            Err(err) if err.kind() != io::ErrorKind::AlreadyExists => return Err(err),
+            _ => {},
+        }
+    }
+    Ok(())
+}
+
+/// Moves `from` to `to`, replacing what's there, if `from` exists.
+pub(crate) fn move_if_exists(storage: &dyn StorageBackend, from: &str, to: &str) -> io::Result<()> {
+    match storage.stat(from) {
+        Ok(_) => {},
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    }
+    match storage.delete(to) {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+        _ => {},
+    }
+    if let Some((parent, _)) = to.rsplit_once('/') {
+        mkdir_all(storage, parent)?;
+    }
+    storage.rename(from, to)
+}
+
+pub(crate) fn now_nanos() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_nanos() as u64
+}
+
+pub struct Versions {
+    pub retention: Retention,
+}
+impl Versions {
+    pub fn new(retention: Retention) -> Self {
+        Self { retention }
+    }
+
+    /// Copies the file at `path`, if there is one, into its history.
+    /// Called before the file is overwritten.
+    pub fn preserve(&self, storage: &dyn StorageBackend, path: &str) -> io::Result<()> {
+        if self.retention.max_count == 0 {
+            return Ok(());
+        }
+        match storage.stat(path) {
+            Ok(metadata) if !metadata.is_dir => {},
+            Ok(_) => return Ok(()),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        }
+        let dir = version_dir(path);
+        mkdir_all(storage, &dir)?;
+        let data = storage.read(path)?;
+        storage.write(&format!("{dir}/{}", now_nanos()), &mut data.as_slice())?;
+        self.prune(storage, path)
+    }
+
+    pub fn list(&self, storage: &dyn StorageBackend, path: &str) -> io::Result<Vec<VersionInfo>> {
+        let entries = match storage.list(&version_dir(path)) {
+            Ok(entries) => entries,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        let mut versions: Vec<_> = entries.into_iter()
+            .filter(|(_, metadata)| !metadata.is_dir)
+            .filter_map(|(name, metadata)| Some(VersionInfo { id: name.parse().ok()?, len: metadata.len }))
+            .collect();
+        versions.sort_by_key(|x| std::cmp::Reverse(x.id));
+        Ok(versions)
+    }
+
+    pub fn read(&self, storage: &dyn StorageBackend, path: &str, id: u64) -> io::Result<Vec<u8>> {
+        storage.read(&format!("{}/{id}", version_dir(path)))
+    }
+
+    /// Puts version `id` back in place, keeping the current contents as a new version.
+    pub fn restore(&self, storage: &dyn StorageBackend, path: &str, id: u64) -> io::Result<()> {
+        let data = self.read(storage, path, id)?;
+        self.preserve(storage, path)?;
+        storage.write(path, &mut data.as_slice())?;
+        Ok(())
+    }
+
+    /// Moves the history of `from`, and of everything inside it, over to `to`.
+    /// Called after the file or directory itself was renamed.
+    pub fn rename(&self, storage: &dyn StorageBackend, from: &str, to: &str) -> io::Result<()> {
+        move_if_exists(storage, &version_dir(from), &version_dir(to))
+    }
+
+    /// Drops the versions of `path` that fall outside the retention.
+    pub fn prune(&self, storage: &dyn StorageBackend, path: &str) -> io::Result<()> {
+        let oldest = self.retention.max_age.map(|x| now_nanos().saturating_sub(x.as_nanos() as u64));
+        for (i, version) in self.list(storage, path)?.into_iter().enumerate() {
+            if i >= self.retention.max_count || oldest.is_some_and(|x| version.id < x) {
+                storage.delete(&format!("{}/{}", version_dir(path), version.id))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Prunes the history of every file.
+    pub fn prune_all(&self, storage: &dyn StorageBackend) -> io::Result<()> {
+        let mut stack = vec![String::new()];
+        while let Some(dir) = stack.pop() {
+            let entries = match storage.list(&version_dir(&dir)) {
+                Ok(entries) => entries,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+                Err(err) => return Err(err),
+            };
+            let mut has_versions = false;
+            for (name, metadata) in entries {
+                if metadata.is_dir {
+                    stack.push(if dir.is_empty() { name } else { format!("{dir}/{name}") });
+                } else {
+                    has_versions = true;
+                }
+            }
+            if has_versions {
+                self.prune(storage, &dir)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::storage::MemoryBackend;
+
+    /// Writes `contents` to `path` one after the other, keeping the history.
+    fn overwrite(versions: &Versions, storage: &dyn StorageBackend, path: &str, contents: &[&[u8]]) {
+        for data in contents {
+            versions.preserve(storage, path).unwrap();
+            storage.write(path, &mut &data[..]).unwrap();
+        }
+    }
+
+    #[test]
+    fn keeps_the_newest_versions() {
+        let storage = MemoryBackend::new();
+        let versions = Versions::new(Retention { max_count: 2, max_age: None });
+        overwrite(&versions, &storage, "a", &[b"1", b"22", b"333", b"4444"]);
+        let list = versions.list(&storage, "a").unwrap();
+        assert_eq!(list.iter().map(|x| x.len).collect::<Vec<_>>(), [3, 2]);
+        assert_eq!(versions.read(&storage, "a", list[0].id).unwrap(), b"333");
+
+        versions.restore(&storage, "a", list[1].id).unwrap();
+        assert_eq!(storage.read("a").unwrap(), b"22");
+        // what was replaced by the restore is the newest version now
+        let list = versions.list(&storage, "a").unwrap();
+        assert_eq!(versions.read(&storage, "a", list[0].id).unwrap(), b"4444");
+    }
+
+    #[test]
+    fn prunes_old_versions() {
+        let storage = MemoryBackend::new();
+        let mut versions = Versions::new(Retention { max_count: 10, max_age: None });
+        storage.mkdir("dir").unwrap();
+        overwrite(&versions, &storage, "dir/a", &[b"1", b"2", b"3"]);
+        assert_eq!(versions.list(&storage, "dir/a").unwrap().len(), 2);
+
+        versions.retention.max_age = Some(Duration::ZERO);
+        versions.prune_all(&storage).unwrap();
+        assert!(versions.list(&storage, "dir/a").unwrap().is_empty());
+        assert_eq!(storage.read("dir/a").unwrap(), b"3");
+    }
+
+    #[test]
+    fn zero_versions_keeps_no_history() {
+        let storage = MemoryBackend::new();
+        let versions = Versions::new(Retention { max_count: 0, max_age: None });
+        overwrite(&versions, &storage, "a", &[b"1", b"2"]);
+        assert!(versions.list(&storage, "a").unwrap().is_empty());
+        assert!(storage.stat(VERSIONS_DIR).is_err());
+    }
+
+    #[test]
+    fn history_follows_renames() {
+        let storage = MemoryBackend::new();
+        let versions = Versions::new(Retention { max_count: 10, max_age: None });
+        storage.mkdir("dir").unwrap();
+        overwrite(&versions, &storage, "dir/a", &[b"1", b"2"]);
+        overwrite(&versions, &storage, "b", &[b"1", b"2"]);
+
+        storage.rename("dir", "moved").unwrap();
+        versions.rename(&storage, "dir", "moved").unwrap();
+        assert!(versions.list(&storage, "dir/a").unwrap().is_empty());
+        let list = versions.list(&storage, "moved/a").unwrap();
+        assert_eq!(versions.read(&storage, "moved/a", list[0].id).unwrap(), b"1");
+
+        storage.rename("b", "moved/b").unwrap();
+        versions.rename(&storage, "b", "moved/b").unwrap();
+        assert_eq!(versions.list(&storage, "moved/b").unwrap().len(), 1);
+        // nothing to move
+        versions.rename(&storage, "c", "d").unwrap();
+    }
+}